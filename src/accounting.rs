@@ -0,0 +1,25 @@
+//! Bandwidth accounting: the bytes each user relays, reported bit by bit
+//! while sessions run, to build quotas or billing on.
+
+/// Receives the bytes relayed by each user since the last report.
+///
+/// Reports come every accounting interval for running sessions, and once
+/// more when a session ends. Clients that did not authenticate are
+/// reported with no user.
+pub trait Accounting: Send + Sync {
+    fn record(&self, user: Option<&str>, bytes_up: u64, bytes_down: u64);
+}
+
+/// Logs every report.
+pub struct LogAccounting;
+
+impl Accounting for LogAccounting {
+    fn record(&self, user: Option<&str>, bytes_up: u64, bytes_down: u64) {
+        info!(
+            "Usage of {}: {} bytes up, {} bytes down",
+            user.unwrap_or("unauthenticated clients"),
+            bytes_up,
+            bytes_down
+        );
+    }
+}
@@ -0,0 +1,312 @@
+use crate::auth::Identity;
+use crate::domain_filter::DomainFilter;
+use fast_socks5::util::target_addr::TargetAddr;
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Decides whether a client may reach a destination.
+pub trait AccessPolicy {
+    /// `domain` is the hostname the client asked for, if any, and `target` the
+    /// address it resolved to.
+    fn allows(&self, identity: &Identity, domain: Option<&str>, target: &TargetAddr) -> bool;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(Action::Allow),
+            "deny" => Ok(Action::Deny),
+            _ => Err(format!(
+                "unknown ACL action `{}`, expected allow or deny",
+                s
+            )),
+        }
+    }
+}
+
+/// What a rule applies to, shared with the rewrite rules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    Any,
+    Network(IpNet),
+    /// `example.com`: only this exact name.
+    Domain(String),
+    /// `.example.com`: the name itself and all of its subdomains.
+    Suffix(String),
+    /// `*.example.com`: subdomains only.
+    Wildcard(String),
+}
+
+impl Destination {
+    /// `domain` must be lowercase, without the trailing dot.
+    pub fn matches(&self, domain: Option<&str>, ip: Option<IpAddr>) -> bool {
+        match self {
+            Destination::Any => true,
+            Destination::Network(net) => ip.is_some_and(|ip| net.contains(&ip.to_canonical())),
+            Destination::Domain(name) => domain.is_some_and(|d| d == name),
+            Destination::Suffix(suffix) => {
+                domain.is_some_and(|d| d == &suffix[1..] || d.ends_with(suffix.as_str()))
+            }
+            Destination::Wildcard(suffix) => domain.is_some_and(|d| d.ends_with(suffix.as_str())),
+        }
+    }
+}
+
+impl FromStr for Destination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "any" || s == "*" {
+            return Ok(Destination::Any);
+        }
+        if let Ok(net) = s.parse::<IpNet>() {
+            return Ok(Destination::Network(net));
+        }
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(Destination::Network(ip.into()));
+        }
+
+        let name = s.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(suffix) = name.strip_prefix("*.") {
+            Ok(Destination::Wildcard(format!(".{}", suffix)))
+        } else if name.starts_with('.') && name.len() > 1 {
+            Ok(Destination::Suffix(name))
+        } else if !name.is_empty() && !name.contains('*') {
+            Ok(Destination::Domain(name))
+        } else {
+            Err(format!("invalid destination `{}`", s))
+        }
+    }
+}
+
+/// A single ACL rule: `<allow|deny> <destination> [port[-port]] [user:<name>]`.
+///
+/// The destination is `any`, a CIDR or IP address, or a domain name in one of
+/// the forms `example.com`, `.example.com` or `*.example.com`. Rules with a
+/// user only apply to clients authenticated as that user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    action: Action,
+    destination: Destination,
+    ports: Option<(u16, u16)>,
+    /// The only user the rule applies to, if any.
+    user: Option<String>,
+}
+
+impl Rule {
+    fn matches(
+        &self,
+        user: Option<&str>,
+        domain: Option<&str>,
+        ip: Option<IpAddr>,
+        port: u16,
+    ) -> bool {
+        self.user.as_deref().is_none_or(|only| user == Some(only))
+            && self
+                .ports
+                .is_none_or(|(first, last)| (first..=last).contains(&port))
+            && self.destination.matches(domain, ip)
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split_whitespace();
+        let action: Action = fields.next().ok_or("empty ACL rule")?.parse()?;
+        let destination: Destination = fields
+            .next()
+            .ok_or_else(|| format!("ACL rule `{}` has no destination", s))?
+            .parse()?;
+        let mut ports = None;
+        let mut user = None;
+        for field in fields {
+            match field.strip_prefix("user:") {
+                Some(name) if user.is_none() => user = Some(name.to_string()),
+                None if ports.is_none() && user.is_none() => ports = Some(parse_port_range(field)?),
+                _ => return Err(format!("trailing fields in ACL rule `{}`", s)),
+            }
+        }
+
+        Ok(Rule {
+            action,
+            destination,
+            ports,
+            user,
+        })
+    }
+}
+
+pub fn parse_port_range(s: &str) -> Result<(u16, u16), String> {
+    let invalid = || format!("invalid port range `{}`", s);
+    let (first, last) = match s.split_once('-') {
+        Some((first, last)) => (first, last),
+        None => (s, s),
+    };
+    let first = first.parse().map_err(|_| invalid())?;
+    let last = last.parse().map_err(|_| invalid())?;
+    if first > last {
+        return Err(invalid());
+    }
+    Ok((first, last))
+}
+
+/// An ordered rule list: the first matching rule decides, otherwise the
+/// default action applies.
+///
+/// Domains on the blocklist are denied ahead of the rules, unless they are
+/// on the allowlist too.
+pub struct Acl<'a> {
+    rules: &'a [Rule],
+    default: Action,
+    blocklist: Option<&'a DomainFilter>,
+    allowlist: Option<&'a DomainFilter>,
+}
+
+impl<'a> Acl<'a> {
+    pub fn new(rules: &'a [Rule], default: Action) -> Self {
+        Acl {
+            rules,
+            default,
+            blocklist: None,
+            allowlist: None,
+        }
+    }
+
+    pub fn with_domain_lists(
+        self,
+        blocklist: Option<&'a DomainFilter>,
+        allowlist: Option<&'a DomainFilter>,
+    ) -> Self {
+        Acl {
+            blocklist,
+            allowlist,
+            ..self
+        }
+    }
+
+    fn blocked(&self, domain: &str) -> bool {
+        self.blocklist.is_some_and(|list| list.contains(domain))
+            && !self.allowlist.is_some_and(|list| list.contains(domain))
+    }
+}
+
+impl AccessPolicy for Acl<'_> {
+    fn allows(&self, identity: &Identity, domain: Option<&str>, target: &TargetAddr) -> bool {
+        let (domain, ip, port) = match target {
+            TargetAddr::Ip(addr) => (domain, Some(addr.ip()), addr.port()),
+            TargetAddr::Domain(name, port) => (Some(domain.unwrap_or(name)), None, *port),
+        };
+        let domain = domain.map(|d| d.trim_end_matches('.').to_ascii_lowercase());
+        if domain.as_deref().is_some_and(|d| self.blocked(d)) {
+            return false;
+        }
+
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(identity.user.as_deref(), domain.as_deref(), ip, port))
+            .map_or(self.default, |rule| rule.action)
+            == Action::Allow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Method;
+
+    fn rules(rules: &[&str]) -> Vec<Rule> {
+        rules.iter().map(|rule| rule.parse().unwrap()).collect()
+    }
+
+    fn domain(name: &str, port: u16) -> TargetAddr {
+        TargetAddr::Domain(name.to_string(), port)
+    }
+
+    fn ip(addr: &str) -> TargetAddr {
+        TargetAddr::Ip(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn parses_rules() {
+        assert_eq!(
+            "deny .Example.com. 8000-8080 user:alice".parse(),
+            Ok(Rule {
+                action: Action::Deny,
+                destination: Destination::Suffix(".example.com".to_string()),
+                ports: Some((8000, 8080)),
+                user: Some("alice".to_string()),
+            })
+        );
+        assert_eq!(
+            "allow 10.0.0.1 443"
+                .parse::<Rule>()
+                .map(|rule| rule.destination),
+            Ok(Destination::Network("10.0.0.1/32".parse().unwrap()))
+        );
+        assert_eq!(
+            "allow *.example.com"
+                .parse::<Rule>()
+                .map(|rule| rule.destination),
+            Ok(Destination::Wildcard(".example.com".to_string()))
+        );
+        assert_eq!(
+            "allow *".parse::<Rule>().map(|rule| rule.destination),
+            Ok(Destination::Any)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_rules() {
+        for rule in [
+            "",
+            "allow",
+            "permit any",
+            "allow a*b.com",
+            "allow any 443-80",
+            "allow any 70000",
+            "allow any 80 443",
+            "allow any user:alice 80",
+            "allow any user:alice user:bob",
+        ] {
+            assert!(rule.parse::<Rule>().is_err(), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let rules = rules(&[
+            "deny 10.0.0.0/8",
+            "allow .example.com 443",
+            "deny *.internal.test",
+            "allow any 22 user:alice",
+        ]);
+        let acl = Acl::new(&rules, Action::Allow);
+        let anonymous = Identity::anonymous(Method::None);
+        assert!(!acl.allows(&anonymous, None, &ip("10.1.2.3:443")));
+        assert!(!acl.allows(&anonymous, None, &ip("[::ffff:10.1.2.3]:443")));
+        assert!(acl.allows(&anonymous, None, &ip("192.0.2.1:443")));
+        assert!(!acl.allows(&anonymous, Some("www.example.com"), &ip("10.1.2.3:443")));
+        assert!(acl.allows(&anonymous, None, &domain("internal.test", 80)));
+        assert!(!acl.allows(&anonymous, None, &domain("db.Internal.test.", 80)));
+
+        let acl = Acl::new(&rules, Action::Deny);
+        assert!(acl.allows(&anonymous, None, &domain("example.com", 443)));
+        assert!(acl.allows(&anonymous, None, &domain("www.example.com", 443)));
+        assert!(!acl.allows(&anonymous, None, &domain("notexample.com", 443)));
+        assert!(!acl.allows(&anonymous, None, &domain("www.example.com", 80)));
+        assert!(!acl.allows(&anonymous, None, &ip("192.0.2.1:22")));
+        assert!(acl.allows(&Identity::user("alice"), None, &ip("192.0.2.1:22")));
+        assert!(!acl.allows(&Identity::user("bob"), None, &ip("192.0.2.1:22")));
+    }
+}
@@ -0,0 +1,191 @@
+//! A small HTTP API for operators, built with the `admin` feature:
+//!
+//! - `GET /health` answers `ok` while the server runs.
+//! - `GET /metrics` renders the Prometheus metrics, with the `metrics` feature.
+//! - `GET /sessions` lists the active sessions as JSON.
+//! - `DELETE /sessions/<id>` ends a session.
+
+use crate::monitoring;
+use crate::registry::Registry;
+use serde_json::json;
+use std::io;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task;
+use tokio::time::timeout;
+
+/// Upper bound for the request line and headers together.
+const MAX_HEAD_LEN: usize = 8192;
+
+/// How long a client gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn text(status: u16, body: &str) -> Self {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: format!("{}\n", body),
+        }
+    }
+}
+
+/// Answer admin requests on `listener`, requiring `Authorization: Bearer
+/// <token>` when `token` is set.
+pub async fn serve(
+    listener: TcpListener,
+    registry: &'static Registry,
+    token: Option<&'static str>,
+) {
+    loop {
+        let (mut socket, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("Can't accept admin connection: {}", err);
+                continue;
+            }
+        };
+        task::spawn(async move {
+            if let Err(err) = handle(&mut socket, registry, token).await {
+                debug!("Admin request from {} failed: {}", peer, err);
+            }
+        });
+    }
+}
+
+async fn handle(
+    socket: &mut TcpStream,
+    registry: &Registry,
+    token: Option<&str>,
+) -> io::Result<()> {
+    let head = timeout(REQUEST_TIMEOUT, read_head(socket))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (method, path) = (
+        request_line.next().unwrap_or_default(),
+        request_line.next().unwrap_or_default(),
+    );
+    let authorized = token.is_none_or(|token| {
+        lines
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| name.trim().eq_ignore_ascii_case("authorization"))
+            .filter_map(|(_, value)| value.trim().strip_prefix("Bearer "))
+            .any(|presented| same_token(presented, token))
+    });
+
+    let response = if authorized {
+        route(method, path, registry)
+    } else {
+        Response::text(401, "unauthorized")
+    };
+    info!(
+        "Admin {} {} from {}: {}",
+        method,
+        path,
+        socket.peer_addr()?,
+        response.status
+    );
+    write_response(socket, response).await
+}
+
+/// Compare tokens in time independent of where they differ, so that
+/// response times don't give the token away byte by byte.
+fn same_token(presented: &str, token: &str) -> bool {
+    presented.len() == token.len()
+        && presented
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn route(method: &str, path: &str, registry: &Registry) -> Response {
+    match (method, path) {
+        ("GET", "/health") => Response::text(200, "ok"),
+        ("GET", "/metrics") => match monitoring::render() {
+            Some(metrics) => Response {
+                status: 200,
+                content_type: "text/plain; version=0.0.4",
+                body: metrics,
+            },
+            None => Response::text(404, "built without the metrics feature"),
+        },
+        ("GET", "/sessions") => {
+            let sessions: Vec<_> = registry
+                .list()
+                .into_iter()
+                .map(|session| {
+                    json!({
+                        "id": session.id,
+                        "peer": session.peer.to_string(),
+                        "user": session.user,
+                        "auth_method": session.auth_method.map(|method| method.as_str()),
+                        "command": session.command,
+                        "target": session.target,
+                        "bytes_up": session.bytes_up,
+                        "bytes_down": session.bytes_down,
+                        "started": session
+                            .started
+                            .duration_since(UNIX_EPOCH)
+                            .map_or(0, |since| since.as_secs()),
+                    })
+                })
+                .collect();
+            Response {
+                status: 200,
+                content_type: "application/json",
+                body: serde_json::Value::from(sessions).to_string(),
+            }
+        }
+        ("DELETE", path) => match path.strip_prefix("/sessions/").map(str::parse::<u64>) {
+            Some(Ok(id)) if registry.kill(id) => Response::text(200, "killed"),
+            Some(Ok(_)) => Response::text(404, "no such session"),
+            _ => Response::text(404, "not found"),
+        },
+        _ => Response::text(404, "not found"),
+    }
+}
+
+/// Read the request line and headers; request bodies are never needed.
+async fn read_head(socket: &mut TcpStream) -> io::Result<String> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() == MAX_HEAD_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "admin request head exceeds 8 KiB",
+            ));
+        }
+        head.push(socket.read_u8().await?);
+    }
+    String::from_utf8(head)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "admin request is not UTF-8"))
+}
+
+async fn write_response(socket: &mut TcpStream, response: Response) -> io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        401 => "Unauthorized",
+        _ => "Not Found",
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len()
+    );
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(response.body.as_bytes()).await?;
+    socket.shutdown().await
+}
@@ -0,0 +1,83 @@
+//! One structured record per finished session, for environments that must
+//! keep an audit trail.
+
+use serde_json::json;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What a session did, written once it is over.
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    pub started: SystemTime,
+    pub ended: SystemTime,
+    pub user: Option<String>,
+    /// How the client authenticated, if it got that far.
+    pub auth_method: Option<&'static str>,
+    pub client: SocketAddr,
+    pub command: Option<String>,
+    pub target: Option<String>,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    /// How the relay ended, or why the session failed.
+    pub close_reason: String,
+    /// The reply sent to the client: a SOCKS reply code, or an HTTP status
+    /// for HTTP CONNECT.
+    pub reply: Option<u16>,
+    /// Where the target is, with the `geoip` feature.
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+/// Receives the record of every finished session.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, record: &AuditRecord);
+}
+
+/// Appends records to a file, one JSON object per line.
+pub struct JsonLinesSink {
+    file: Mutex<File>,
+}
+
+impl JsonLinesSink {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JsonLinesSink {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl AuditSink for JsonLinesSink {
+    fn record(&self, record: &AuditRecord) {
+        let mut line = json!({
+            "started": unix_seconds(record.started),
+            "ended": unix_seconds(record.ended),
+            "user": record.user,
+            "auth_method": record.auth_method,
+            "client": record.client.to_string(),
+            "command": record.command,
+            "target": record.target,
+            "bytes_up": record.bytes_up,
+            "bytes_down": record.bytes_down,
+            "close_reason": record.close_reason,
+            "reply": record.reply,
+            "country": record.country,
+            "asn": record.asn,
+        })
+        .to_string();
+        line.push('\n');
+        // One write per line, so that concurrent records never interleave.
+        if let Err(err) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            error!("Can't write audit record: {}", err);
+        }
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0.0, |since| since.as_secs_f64())
+}
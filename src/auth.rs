@@ -0,0 +1,373 @@
+//! Password verification against a file of users with hashed passwords, and
+//! throttling of clients that keep failing it.
+
+use crate::client_stream::Source;
+use crate::rate_limit::TokenBucket;
+use crate::totp::Secret;
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use fast_socks5::Socks5Command;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
+
+/// How a client got through authentication.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// The listener asks for no credentials.
+    None,
+    Password,
+    /// The client's network is exempt from authentication.
+    Trusted,
+    /// The client runs as the user, on a Unix socket listener with
+    /// `auth=peer`.
+    Peer,
+    /// The client presented a TLS certificate naming the user.
+    #[cfg(feature = "tls")]
+    Certificate,
+}
+
+impl Method {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Method::None => "none",
+            Method::Password => "password",
+            Method::Trusted => "trusted",
+            Method::Peer => "peer",
+            #[cfg(feature = "tls")]
+            Method::Certificate => "certificate",
+        }
+    }
+}
+
+/// Who a client proved to be, and how, handed to the ACL and kept with the
+/// session for the audit record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub user: Option<String>,
+    pub method: Method,
+}
+
+impl Identity {
+    pub fn anonymous(method: Method) -> Self {
+        Identity { user: None, method }
+    }
+
+    pub fn user(user: &str) -> Self {
+        Identity {
+            user: Some(user.to_string()),
+            method: Method::Password,
+        }
+    }
+
+    pub fn peer(user: &str) -> Self {
+        Identity {
+            user: Some(user.to_string()),
+            method: Method::Peer,
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn certificate(user: &str) -> Self {
+        Identity {
+            user: Some(user.to_string()),
+            method: Method::Certificate,
+        }
+    }
+}
+
+/// The login name of `uid`, or the number when it has none. Looking it up
+/// may block on NSS.
+#[cfg(unix)]
+pub fn peer_user(uid: u32) -> String {
+    match nix::unistd::User::from_uid(nix::unistd::Uid::from_raw(uid)) {
+        Ok(Some(user)) => user.name,
+        _ => uid.to_string(),
+    }
+}
+
+/// Sources tracked before forgotten ones are purged.
+const MAX_TRACKED_SOURCES: usize = 4096;
+
+/// Users and their password hashes, read from an htpasswd-style file of
+/// `user:hash` lines. Hashes are argon2 (`$argon2id$...`, e.g. from the
+/// `argon2` CLI) or bcrypt (`$2y$...`, e.g. from `htpasswd -B`). Blank lines
+/// and lines starting with `#` are skipped.
+///
+/// A line may end with a third field, `user:hash:secret`, holding a base32
+/// TOTP secret. That user then logs in with `password:code` as the password,
+/// each code only once.
+///
+/// Options may follow, separated by spaces: `commands=connect,bind,udp`
+/// limits the commands the user may send, and `rate=<bytes>` caps the
+/// bandwidth of all of the user's sessions together, in bytes per second.
+pub struct UserStore {
+    users: HashMap<String, User>,
+}
+
+struct User {
+    hash: String,
+    totp: Option<Secret>,
+    /// The TOTP step of the last code accepted, which no code of the same or
+    /// an earlier step may follow.
+    last_step: AtomicU64,
+    /// Every command when not set.
+    commands: Option<Vec<Socks5Command>>,
+    rate: Option<Arc<TokenBucket>>,
+}
+
+impl UserStore {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let mut users = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: {}", path.display(), number + 1, reason),
+                )
+            };
+            let mut fields = line.split_whitespace();
+            let credentials = fields.next().unwrap_or_default();
+            let mut commands = None;
+            let mut rate = None;
+            for option in fields {
+                match option.split_once('=') {
+                    Some(("commands", list)) => {
+                        let list = list
+                            .split(',')
+                            .map(parse_command)
+                            .collect::<Option<_>>()
+                            .ok_or_else(|| invalid("commands are connect, bind and udp"))?;
+                        commands = Some(list);
+                    }
+                    Some(("rate", bytes)) => {
+                        let bytes = bytes
+                            .parse()
+                            .ok()
+                            .filter(|bytes| *bytes > 0)
+                            .ok_or_else(|| invalid("the rate must be a number of bytes"))?;
+                        rate = Some(Arc::new(TokenBucket::new(bytes)));
+                    }
+                    _ => {
+                        return Err(invalid(&format!(
+                            "unknown option `{}`, expected commands= or rate=",
+                            option
+                        )));
+                    }
+                }
+            }
+            let (user, hash) = credentials
+                .split_once(':')
+                .ok_or_else(|| invalid("expected `user:hash`"))?;
+            let (hash, totp) = match hash.split_once(':') {
+                Some((hash, secret)) => {
+                    let secret = Secret::from_base32(secret)
+                        .ok_or_else(|| invalid("the TOTP secret must be base32"))?;
+                    (hash, Some(secret))
+                }
+                None => (hash, None),
+            };
+            if !is_argon2(hash) && !is_bcrypt(hash) {
+                return Err(invalid("the password must be an argon2 or bcrypt hash"));
+            }
+            let entry = User {
+                hash: hash.to_string(),
+                totp,
+                last_step: AtomicU64::new(0),
+                commands,
+                rate,
+            };
+            if users.insert(user.to_string(), entry).is_some() {
+                return Err(invalid("duplicate user"));
+            }
+        }
+        Ok(UserStore { users })
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    /// Whether `user` may send `command`.
+    pub fn allows_command(&self, user: &str, command: &Socks5Command) -> bool {
+        self.users
+            .get(user)
+            .and_then(|user| user.commands.as_ref())
+            .is_none_or(|commands| commands.contains(command))
+    }
+
+    /// The bandwidth cap shared by the sessions of `user`, if it has one.
+    pub fn rate_limit(&self, user: &str) -> Option<Arc<TokenBucket>> {
+        self.users.get(user).and_then(|user| user.rate.clone())
+    }
+
+    /// Whether `password` is the password of `user`, followed by `:` and a
+    /// current TOTP code not used before for users with a secret.
+    ///
+    /// Unknown users are checked against another user's hash, so that timing
+    /// doesn't tell which users exist. The comparison itself is constant time.
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        match self.users.get(user) {
+            Some(User {
+                hash,
+                totp: Some(secret),
+                last_step,
+                ..
+            }) => {
+                // The hash is checked whatever the code, so that timing
+                // doesn't tell which part was wrong, or that there is one.
+                let (password, code) = password.rsplit_once(':').unwrap_or((password, ""));
+                let step = secret.verify(code, SystemTime::now());
+                verify_hash(hash, password)
+                    && step.is_some_and(|step| last_step.fetch_max(step, Ordering::Relaxed) < step)
+            }
+            Some(User {
+                hash, totp: None, ..
+            }) => verify_hash(hash, password),
+            None => {
+                if let Some(other) = self.users.values().next() {
+                    verify_hash(&other.hash, password);
+                }
+                false
+            }
+        }
+    }
+}
+
+fn parse_command(name: &str) -> Option<Socks5Command> {
+    match name {
+        "connect" => Some(Socks5Command::TCPConnect),
+        "bind" => Some(Socks5Command::TCPBind),
+        "udp" => Some(Socks5Command::UDPAssociate),
+        _ => None,
+    }
+}
+
+/// Slows down, then bans, client sources with repeated failed
+/// authentications.
+///
+/// Failures are forgotten after a ban's duration without any.
+pub struct Throttle {
+    /// Failures after which every attempt is delayed.
+    delay_after: u32,
+    delay: Duration,
+    /// Failures after which the source is banned; zero never bans.
+    ban_after: u32,
+    ban: Duration,
+    sources: Mutex<HashMap<Source, Failures>>,
+    on_ban: Box<dyn Fn(Source, u32) + Send + Sync>,
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    banned_until: Option<Instant>,
+}
+
+impl Throttle {
+    /// `on_ban` is called with the address and its failure count when a
+    /// source gets banned, e.g. to feed a firewall.
+    pub fn new(
+        delay_after: u32,
+        delay: Duration,
+        ban_after: u32,
+        ban: Duration,
+        on_ban: impl Fn(Source, u32) + Send + Sync + 'static,
+    ) -> Self {
+        Throttle {
+            delay_after,
+            delay,
+            ban_after,
+            ban,
+            sources: Mutex::new(HashMap::new()),
+            on_ban: Box::new(on_ban),
+        }
+    }
+
+    pub fn is_banned(&self, source: Source) -> bool {
+        let sources = self.sources.lock().unwrap();
+        sources
+            .get(&source)
+            .and_then(|failures| failures.banned_until)
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    /// How long to wait before letting `source` try to authenticate.
+    pub fn delay(&self, source: Source) -> Duration {
+        let sources = self.sources.lock().unwrap();
+        match sources.get(&source) {
+            Some(failures)
+                if failures.count >= self.delay_after && failures.last.elapsed() < self.ban =>
+            {
+                self.delay
+            }
+            _ => Duration::ZERO,
+        }
+    }
+
+    pub fn failed(&self, source: Source) {
+        let mut sources = self.sources.lock().unwrap();
+        if sources.len() >= MAX_TRACKED_SOURCES && !sources.contains_key(&source) {
+            let ban = self.ban;
+            sources.retain(|_, failures| {
+                failures.last.elapsed() < ban
+                    || failures
+                        .banned_until
+                        .is_some_and(|until| Instant::now() < until)
+            });
+        }
+
+        let now = Instant::now();
+        let failures = sources.entry(source).or_insert(Failures {
+            count: 0,
+            last: now,
+            banned_until: None,
+        });
+        if now.duration_since(failures.last) >= self.ban {
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.last = now;
+        if self.ban_after > 0 && failures.count >= self.ban_after {
+            failures.banned_until = Some(now + self.ban);
+            let count = failures.count;
+            drop(sources);
+            (self.on_ban)(source, count);
+        }
+    }
+
+    pub fn succeeded(&self, source: Source) {
+        self.sources.lock().unwrap().remove(&source);
+    }
+}
+
+fn is_argon2(hash: &str) -> bool {
+    hash.starts_with("$argon2")
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
+
+fn verify_hash(hash: &str, password: &str) -> bool {
+    if is_argon2(hash) {
+        PasswordHash::new(hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    } else {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
@@ -0,0 +1,116 @@
+use crate::client_stream::ClientStream;
+use crate::outbound::Outbound;
+use crate::rate_limit::RateLimiter;
+use crate::relay::{self, ProxyStats, RelayOptions};
+use crate::wire::{
+    self, SOCKS5_REPLY_GENERAL_FAILURE, SOCKS5_REPLY_SUCCEEDED, SOCKS5_REPLY_TTL_EXPIRED,
+    SOCKS5_VERSION,
+};
+use fast_socks5::server::{Socks5ServerProtocol, states};
+use fast_socks5::util::target_addr::TargetAddr;
+use fast_socks5::{ReplyError, Result, SocksError};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::time::{Instant, timeout_at};
+use tokio_util::sync::CancellationToken;
+
+/// Where the BIND socket listens and what address the client is told.
+#[derive(Debug, Clone, Copy)]
+pub struct BindAddrs {
+    /// Local address to listen on, or every address of `reply_ip`'s family.
+    pub listen_ip: Option<IpAddr>,
+    /// Sent to the client as BND.ADDR of the first reply.
+    pub reply_ip: IpAddr,
+}
+
+/// Serve a BIND request as described in RFC 1928, section 4.
+///
+/// A listening socket is opened and its address is sent in the first reply.
+/// Once the remote host connects, its address is sent in the second reply and
+/// the two streams are relayed until either side closes.
+///
+/// `expected_peer` is the DST.ADDR of the request; when it is set, inbound
+/// connections from any other host are refused.
+pub async fn run_tcp_bind(
+    proto: Socks5ServerProtocol<ClientStream, states::CommandRead>,
+    expected_peer: Option<IpAddr>,
+    addrs: BindAddrs,
+    accept_timeout_s: u64,
+    relay_options: RelayOptions<'_>,
+    limiter: &RateLimiter,
+    shutdown: &CancellationToken,
+) -> Result<ProxyStats> {
+    let BindAddrs {
+        listen_ip,
+        reply_ip,
+    } = addrs;
+    let bind_ip = listen_ip.unwrap_or(match reply_ip {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    });
+    let listener = match TcpListener::bind(SocketAddr::new(bind_ip, 0)).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            proto.reply_error(&ReplyError::GeneralFailure).await?;
+            return Err(err.into());
+        }
+    };
+    let bnd_addr = SocketAddr::new(reply_ip, listener.local_addr()?.port());
+    debug!("BIND listening on {}", bnd_addr);
+
+    // First reply: the address the remote host should connect to.
+    let mut inner = proto.reply_success(bnd_addr).await?;
+
+    let deadline = Instant::now() + Duration::from_secs(accept_timeout_s);
+    let (inbound, peer_addr) = loop {
+        match timeout_at(deadline, listener.accept()).await {
+            Ok(Ok((stream, peer_addr))) => {
+                if expected_peer.is_none_or(|ip| ip.is_unspecified() || ip == peer_addr.ip()) {
+                    break (stream, peer_addr);
+                }
+                warn!(
+                    "BIND refused inbound connection from {}, expected {:?}",
+                    peer_addr, expected_peer
+                );
+            }
+            Ok(Err(err)) => {
+                write_reply(&mut inner, SOCKS5_REPLY_GENERAL_FAILURE, bnd_addr).await?;
+                return Err(err.into());
+            }
+            Err(_) => {
+                write_reply(&mut inner, SOCKS5_REPLY_TTL_EXPIRED, bnd_addr).await?;
+                return Err(SocksError::Other(anyhow::anyhow!(
+                    "no inbound BIND connection on {} after {}s",
+                    bnd_addr,
+                    accept_timeout_s
+                )));
+            }
+        }
+    };
+    drop(listener);
+
+    // Second reply: the address of the host that connected.
+    write_reply(&mut inner, SOCKS5_REPLY_SUCCEEDED, peer_addr).await?;
+    debug!("BIND accepted inbound connection from {}", peer_addr);
+
+    Ok(relay::relay_tcp(
+        inner,
+        Outbound::Tcp(inbound),
+        relay_options,
+        limiter,
+        shutdown,
+    )
+    .await)
+}
+
+async fn write_reply(
+    stream: &mut ClientStream,
+    reply: u8,
+    addr: SocketAddr,
+) -> std::io::Result<()> {
+    let mut packet = vec![SOCKS5_VERSION, reply, 0x00];
+    wire::encode_addr(&mut packet, &TargetAddr::Ip(addr))?;
+    stream.write_all(&packet).await
+}
@@ -0,0 +1,69 @@
+//! Relay buffers reused across sessions, so that a busy server doesn't
+//! allocate fresh ones for every connection.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+/// A pool of equally sized byte buffers.
+pub struct BufferPool {
+    buffer_size: usize,
+    max_idle: usize,
+    idle: Mutex<Vec<Box<[u8]>>>,
+}
+
+impl BufferPool {
+    /// A pool of `buffer_size` byte buffers, keeping at most `max_idle` of
+    /// the returned ones for later.
+    pub fn new(buffer_size: usize, max_idle: usize) -> Self {
+        BufferPool {
+            buffer_size: buffer_size.max(1),
+            max_idle,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    #[cfg(all(feature = "splice", target_os = "linux"))]
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Take a buffer from the pool, allocating one if none is idle. It goes
+    /// back to the pool when dropped.
+    pub fn get(&self) -> PooledBuffer<'_> {
+        let buf = self
+            .idle
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| vec![0u8; self.buffer_size].into_boxed_slice());
+        PooledBuffer { pool: self, buf }
+    }
+}
+
+pub struct PooledBuffer<'a> {
+    pool: &'a BufferPool,
+    buf: Box<[u8]>,
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        let mut idle = self.pool.idle.lock().unwrap();
+        if idle.len() < self.pool.max_idle {
+            idle.push(std::mem::take(&mut self.buf));
+        }
+    }
+}
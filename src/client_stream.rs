@@ -0,0 +1,346 @@
+//! The connection of a client, over plain TCP, TLS, a Unix socket, a QUIC
+//! stream or a WebSocket on one of those, so that every protocol is served
+//! the same way whatever it arrived on.
+
+use std::fmt;
+use std::io::{self, IoSlice};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+#[cfg(any(unix, feature = "tls", feature = "websocket", feature = "quic"))]
+use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+/// What stands in for both addresses of a Unix socket connection, whose
+/// client is on this host.
+pub const LOCAL: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// What the limits and bans on clients count a client against: its address,
+/// or on a Unix socket the user it runs as, since every Unix client has the
+/// same [`LOCAL`] address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Source {
+    Ip(IpAddr),
+    Uid(u32),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Ip(ip) => write!(f, "{}", ip),
+            Source::Uid(uid) => write!(f, "uid {}", uid),
+        }
+    }
+}
+
+/// A client connection.
+pub struct ClientStream {
+    transport: Transport,
+    /// The first byte, when [`ClientStream::peek`] had to read it from a
+    /// transport that can't peek. It is read again before anything else.
+    peeked: Option<u8>,
+}
+
+enum Transport {
+    Tcp(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<tokio_rustls::server::TlsStream<TcpStream>>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(feature = "websocket")]
+    WebSocket(Box<crate::websocket::WebSocket<ClientStream>>),
+    /// With the certificate the client presented for the connection.
+    #[cfg(feature = "quic")]
+    Quic(
+        Box<crate::quic::QuicStream>,
+        Option<tokio_rustls::rustls::Certificate>,
+    ),
+}
+
+impl ClientStream {
+    pub fn tcp(socket: TcpStream) -> Self {
+        ClientStream {
+            transport: Transport::Tcp(socket),
+            peeked: None,
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn unix(socket: UnixStream) -> Self {
+        ClientStream {
+            transport: Transport::Unix(socket),
+            peeked: None,
+        }
+    }
+
+    #[cfg(feature = "quic")]
+    pub fn quic(
+        stream: crate::quic::QuicStream,
+        certificate: Option<tokio_rustls::rustls::Certificate>,
+    ) -> Self {
+        ClientStream {
+            transport: Transport::Quic(Box::new(stream), certificate),
+            peeked: None,
+        }
+    }
+
+    /// Run the TLS handshake of a plain TCP client and carry on over TLS.
+    #[cfg(feature = "tls")]
+    pub async fn accept_tls(self, acceptor: &tokio_rustls::TlsAcceptor) -> io::Result<Self> {
+        let socket = match self.transport {
+            Transport::Tcp(socket) => socket,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "TLS can only be started on a plain TCP connection",
+                ));
+            }
+        };
+        Ok(ClientStream {
+            transport: Transport::Tls(Box::new(acceptor.accept(socket).await?)),
+            peeked: None,
+        })
+    }
+
+    /// Answer the WebSocket upgrade request of the client and carry on with
+    /// the byte stream inside the WebSocket.
+    #[cfg(feature = "websocket")]
+    pub async fn accept_websocket(self) -> io::Result<Self> {
+        Ok(ClientStream {
+            transport: Transport::WebSocket(Box::new(crate::websocket::accept(self).await?)),
+            peeked: None,
+        })
+    }
+
+    /// The TCP socket the connection runs on, for socket options. `None`
+    /// on a Unix socket.
+    pub fn socket(&self) -> Option<&TcpStream> {
+        match &self.transport {
+            Transport::Tcp(socket) => Some(socket),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => Some(stream.get_ref().0),
+            #[cfg(unix)]
+            Transport::Unix(_) => None,
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(stream) => stream.get_ref().socket(),
+            #[cfg(feature = "quic")]
+            Transport::Quic(..) => None,
+        }
+    }
+
+    /// The certificate the client presented in the TLS handshake, verified
+    /// against the client CAs.
+    #[cfg(feature = "tls")]
+    pub fn peer_certificate(&self) -> Option<&tokio_rustls::rustls::Certificate> {
+        match &self.transport {
+            Transport::Tls(stream) => stream.get_ref().1.peer_certificates()?.first(),
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(stream) => stream.get_ref().peer_certificate(),
+            #[cfg(feature = "quic")]
+            Transport::Quic(_, certificate) => certificate.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// The user, group and process of the client of a Unix socket, as the
+    /// kernel tells them (`SO_PEERCRED`).
+    #[cfg(unix)]
+    pub fn peer_cred(&self) -> io::Result<tokio::net::unix::UCred> {
+        match &self.transport {
+            Transport::Unix(socket) => socket.peer_cred(),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "not a Unix socket connection",
+            )),
+        }
+    }
+
+    /// The TCP socket, when the connection is nothing more, so that what is
+    /// written to it can go to the client untouched, e.g. with `splice(2)`.
+    #[cfg(all(feature = "splice", target_os = "linux"))]
+    pub fn plain_tcp(&self) -> Option<&TcpStream> {
+        match &self.transport {
+            Transport::Tcp(socket) => Some(socket),
+            #[cfg(feature = "tls")]
+            Transport::Tls(_) => None,
+            #[cfg(unix)]
+            Transport::Unix(_) => None,
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(_) => None,
+            #[cfg(feature = "quic")]
+            Transport::Quic(..) => None,
+        }
+    }
+
+    /// The source of the client connected from `client_addr`.
+    pub fn source(&self, client_addr: SocketAddr) -> Source {
+        #[cfg(unix)]
+        if let Ok(cred) = self.peer_cred() {
+            return Source::Uid(cred.uid());
+        }
+        Source::Ip(client_addr.ip().to_canonical())
+    }
+
+    /// The local address the client connected to, [`LOCAL`] on a Unix
+    /// socket.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        #[cfg(feature = "quic")]
+        if let Transport::Quic(stream, _) = &self.transport {
+            return Ok(stream.local_addr());
+        }
+        match self.socket() {
+            Some(socket) => socket.local_addr(),
+            None => Ok(LOCAL),
+        }
+    }
+
+    /// The first byte the client sends, left to be read again. `0` when the
+    /// client closes without sending anything.
+    pub async fn peek(&mut self) -> io::Result<u8> {
+        if let Some(byte) = self.peeked {
+            return Ok(byte);
+        }
+        let mut byte = [0u8; 1];
+        match &mut self.transport {
+            Transport::Tcp(socket) => {
+                socket.peek(&mut byte).await?;
+            }
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => {
+                if stream.read(&mut byte).await? == 1 {
+                    self.peeked = Some(byte[0]);
+                }
+            }
+            #[cfg(unix)]
+            Transport::Unix(socket) => {
+                if socket.read(&mut byte).await? == 1 {
+                    self.peeked = Some(byte[0]);
+                }
+            }
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(stream) => {
+                if stream.read(&mut byte).await? == 1 {
+                    self.peeked = Some(byte[0]);
+                }
+            }
+            #[cfg(feature = "quic")]
+            Transport::Quic(stream, _) => {
+                if stream.read(&mut byte).await? == 1 {
+                    self.peeked = Some(byte[0]);
+                }
+            }
+        }
+        Ok(byte[0])
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(byte) = this.peeked {
+            if buf.remaining() > 0 {
+                buf.put_slice(&[byte]);
+                this.peeked = None;
+            }
+            return Poll::Ready(Ok(()));
+        }
+        match &mut this.transport {
+            Transport::Tcp(socket) => Pin::new(socket).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Transport::Unix(socket) => Pin::new(socket).poll_read(cx, buf),
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "quic")]
+            Transport::Quic(stream, _) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().transport {
+            Transport::Tcp(socket) => Pin::new(socket).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Transport::Unix(socket) => Pin::new(socket).poll_write(cx, buf),
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "quic")]
+            Transport::Quic(stream, _) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().transport {
+            Transport::Tcp(socket) => Pin::new(socket).poll_write_vectored(cx, bufs),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            #[cfg(unix)]
+            Transport::Unix(socket) => Pin::new(socket).poll_write_vectored(cx, bufs),
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            #[cfg(feature = "quic")]
+            Transport::Quic(stream, _) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match &self.transport {
+            Transport::Tcp(socket) => socket.is_write_vectored(),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.is_write_vectored(),
+            #[cfg(unix)]
+            Transport::Unix(socket) => socket.is_write_vectored(),
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(stream) => stream.is_write_vectored(),
+            #[cfg(feature = "quic")]
+            Transport::Quic(stream, _) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().transport {
+            Transport::Tcp(socket) => Pin::new(socket).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Transport::Unix(socket) => Pin::new(socket).poll_flush(cx),
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "quic")]
+            Transport::Quic(stream, _) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().transport {
+            Transport::Tcp(socket) => Pin::new(socket).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Transport::Unix(socket) => Pin::new(socket).poll_shutdown(cx),
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "quic")]
+            Transport::Quic(stream, _) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! The `--config` file: a TOML table of command line options, keyed by their
+//! long names, with an `[auth]` table for the authentication subcommand:
+//!
+//! ```toml
+//! listen-addr = ["127.0.0.1:1080,auth=none", "0.0.0.0:1081"]
+//! connect-timeout = 10
+//! allow-udp = true
+//! public-addr = "203.0.113.7"
+//! acl = ["allow .example.com 443", "deny any"]
+//! log = "info"
+//!
+//! [auth]
+//! mode = "password"
+//! username = "admin"
+//! password = "secret"
+//! ```
+//!
+//! The file is turned into arguments placed ahead of the real ones, so that
+//! options given on the command line win.
+
+use std::ffi::OsString;
+use std::fs;
+use structopt::clap::{App, ErrorKind};
+use toml::Value;
+
+/// `args` with the options of the `--config` file, if any, merged in.
+pub fn merge_args(app: App<'_, '_>, args: Vec<OsString>) -> Result<Vec<OsString>, String> {
+    let Some(path) = config_path(&args) else {
+        return Ok(args);
+    };
+    let text = fs::read_to_string(&path).map_err(|err| format!("can't read {}: {}", path, err))?;
+    let table: toml::Table = text
+        .parse()
+        .map_err(|err| format!("invalid config file {}: {}", path, err))?;
+
+    let mut options = Vec::new();
+    let mut auth = Vec::new();
+    for (key, value) in &table {
+        match (key.as_str(), value) {
+            ("auth", Value::Table(auth_table)) => auth = subcommand_args(auth_table)?,
+            _ => options.push((key.clone(), option_args(key, value)?)),
+        }
+    }
+
+    // Options of the file only count where the command line is silent, and
+    // its subcommand only when the command line has none.
+    let mut cli = args.clone();
+    let matches = match app.clone().get_matches_from_safe(&cli) {
+        Err(err)
+            if matches!(
+                err.kind,
+                ErrorKind::MissingArgumentOrSubcommand | ErrorKind::MissingSubcommand
+            ) && !auth.is_empty() =>
+        {
+            cli.extend(auth.into_iter().map(OsString::from));
+            app.get_matches_from_safe(&cli)
+        }
+        result => result,
+    };
+    // Leave reporting bad arguments, or showing the help, to the real parse.
+    let Ok(matches) = matches else {
+        return Ok(cli);
+    };
+
+    let mut merged = vec![cli[0].clone()];
+    for (key, args) in options {
+        if matches.occurrences_of(&key) == 0 {
+            merged.extend(args.into_iter().map(OsString::from));
+        }
+    }
+    merged.extend(cli.into_iter().skip(1));
+    Ok(merged)
+}
+
+/// The value of `--config`, read ahead of the full parse since the file
+/// changes what is parsed.
+fn config_path(args: &[OsString]) -> Option<String> {
+    let mut args = args.iter().map(|arg| arg.to_string_lossy());
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(|path| path.into_owned());
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(path.to_string());
+        }
+    }
+    None
+}
+
+/// `--key value`, repeated for arrays; `--key` alone for `true`.
+fn option_args(key: &str, value: &Value) -> Result<Vec<String>, String> {
+    let flag = format!("--{}", key);
+    match value {
+        Value::Boolean(true) => Ok(vec![flag]),
+        Value::Boolean(false) => Ok(vec![]),
+        Value::Array(values) => {
+            let mut args = Vec::new();
+            for value in values {
+                args.push(flag.clone());
+                args.push(scalar(key, value)?);
+            }
+            Ok(args)
+        }
+        value => Ok(vec![flag, scalar(key, value)?]),
+    }
+}
+
+/// The subcommand named by `mode`, followed by its options.
+fn subcommand_args(table: &toml::Table) -> Result<Vec<String>, String> {
+    let mode = match table.get("mode") {
+        Some(Value::String(mode)) => mode.clone(),
+        _ => return Err("the [auth] table needs a mode".to_string()),
+    };
+    let mut args = vec![mode];
+    for (key, value) in table.iter().filter(|(key, _)| *key != "mode") {
+        args.extend(option_args(key, value)?);
+    }
+    Ok(args)
+}
+
+fn scalar(key: &str, value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(n) => Ok(n.to_string()),
+        Value::Float(n) => Ok(n.to_string()),
+        Value::Boolean(b) => Ok(b.to_string()),
+        _ => Err(format!(
+            "config option `{}` must be a string or number",
+            key
+        )),
+    }
+}
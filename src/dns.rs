@@ -0,0 +1,204 @@
+use fast_socks5::util::target_addr::TargetAddr;
+use fast_socks5::{ReplyError, Result};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+#[cfg(feature = "hickory")]
+use std::time::Duration;
+
+#[cfg(feature = "hickory")]
+use hickory_resolver::TokioAsyncResolver;
+#[cfg(any(feature = "dns-over-tls", feature = "dns-over-https"))]
+use hickory_resolver::config::NameServerConfigGroup;
+#[cfg(feature = "hickory")]
+use hickory_resolver::config::{ResolverConfig, ResolverOpts};
+
+/// Which resolver to use for domain targets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResolverKind {
+    /// The operating system resolver.
+    System,
+    /// The system's nameservers, queried by hickory with an in-process cache.
+    #[cfg(feature = "hickory")]
+    Caching,
+    /// A DNS-over-TLS server, see [`EncryptedServer`].
+    #[cfg(feature = "dns-over-tls")]
+    Tls,
+    /// A DNS-over-HTTPS server, see [`EncryptedServer`].
+    #[cfg(feature = "dns-over-https")]
+    Https,
+}
+
+impl FromStr for ResolverKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "system" => Ok(ResolverKind::System),
+            #[cfg(feature = "hickory")]
+            "caching" => Ok(ResolverKind::Caching),
+            #[cfg(feature = "dns-over-tls")]
+            "tls" => Ok(ResolverKind::Tls),
+            #[cfg(feature = "dns-over-https")]
+            "https" => Ok(ResolverKind::Https),
+            _ => Err(format!("unknown resolver `{}`", s)),
+        }
+    }
+}
+
+/// Bounds of the in-process DNS cache.
+#[cfg(feature = "hickory")]
+#[derive(Debug, Clone, Copy)]
+pub struct CacheSettings {
+    /// Maximum number of cached lookups.
+    pub max_entries: usize,
+    /// Upper bound on how long a record is cached, whatever its TTL.
+    pub max_ttl: Duration,
+    /// Upper bound on how long a failed lookup is cached.
+    pub max_negative_ttl: Duration,
+}
+
+/// An encrypted DNS server, given as `name[:port]`, `tls://name[:port]` or
+/// `https://name[:port]/dns-query`.
+///
+/// The name is checked against the server's certificate; the server itself is
+/// reached at the bootstrap addresses so that finding it needs no plaintext
+/// lookup.
+#[cfg(any(feature = "dns-over-tls", feature = "dns-over-https"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncryptedServer {
+    name: String,
+    port: Option<u16>,
+}
+
+#[cfg(any(feature = "dns-over-tls", feature = "dns-over-https"))]
+impl FromStr for EncryptedServer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix("https://")
+            .or_else(|| s.strip_prefix("tls://"))
+            .unwrap_or(s);
+        let authority = match rest.split_once('/') {
+            None | Some((_, "")) => rest.trim_end_matches('/'),
+            // Hickory always queries the standard path.
+            Some((authority, "dns-query")) => authority,
+            Some(_) => return Err(format!("DNS server `{}` must use the /dns-query path", s)),
+        };
+        let (name, port) = match authority.rsplit_once(':') {
+            Some((name, port)) => {
+                let port = port
+                    .parse()
+                    .map_err(|_| format!("invalid port in DNS server `{}`", s))?;
+                (name, Some(port))
+            }
+            None => (authority, None),
+        };
+        if name.is_empty() {
+            return Err(format!("DNS server `{}` has no name", s));
+        }
+        Ok(EncryptedServer {
+            name: name.to_string(),
+            port,
+        })
+    }
+}
+
+/// Resolves the domain targets of client requests.
+pub enum Resolver {
+    System,
+    #[cfg(feature = "hickory")]
+    Hickory(Box<TokioAsyncResolver>),
+}
+
+impl Resolver {
+    /// Build a hickory resolver for the system's nameservers with the given
+    /// cache bounds. Records are cached for their TTL, capped by `cache`.
+    #[cfg(feature = "hickory")]
+    pub fn caching(cache: CacheSettings) -> io::Result<Self> {
+        let (config, opts) =
+            hickory_resolver::system_conf::read_system_conf().map_err(io::Error::other)?;
+        Ok(Resolver::hickory(config, opts, cache))
+    }
+
+    /// Build a resolver that only queries `server` over TLS, at `bootstrap`.
+    #[cfg(feature = "dns-over-tls")]
+    pub fn over_tls(server: &EncryptedServer, bootstrap: &[IpAddr], cache: CacheSettings) -> Self {
+        let servers = NameServerConfigGroup::from_ips_tls(
+            bootstrap,
+            server.port.unwrap_or(853),
+            server.name.clone(),
+            true,
+        );
+        let config = ResolverConfig::from_parts(None, vec![], servers);
+        Resolver::hickory(config, ResolverOpts::default(), cache)
+    }
+
+    /// Build a resolver that only queries `server` over HTTPS, at `bootstrap`.
+    #[cfg(feature = "dns-over-https")]
+    pub fn over_https(
+        server: &EncryptedServer,
+        bootstrap: &[IpAddr],
+        cache: CacheSettings,
+    ) -> Self {
+        let servers = NameServerConfigGroup::from_ips_https(
+            bootstrap,
+            server.port.unwrap_or(443),
+            server.name.clone(),
+            true,
+        );
+        let config = ResolverConfig::from_parts(None, vec![], servers);
+        Resolver::hickory(config, ResolverOpts::default(), cache)
+    }
+
+    #[cfg(feature = "hickory")]
+    fn hickory(config: ResolverConfig, mut opts: ResolverOpts, cache: CacheSettings) -> Self {
+        opts.cache_size = cache.max_entries;
+        opts.positive_max_ttl = Some(cache.max_ttl);
+        opts.negative_max_ttl = Some(cache.max_negative_ttl);
+        Resolver::Hickory(Box::new(TokioAsyncResolver::tokio(config, opts)))
+    }
+
+    /// Look up every address of `host`.
+    pub async fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        match self {
+            Resolver::System => Ok(tokio::net::lookup_host((host, 0))
+                .await?
+                .map(|addr| addr.ip())
+                .collect()),
+            #[cfg(feature = "hickory")]
+            Resolver::Hickory(resolver) => Ok(resolver
+                .lookup_ip(host)
+                .await
+                .map_err(io::Error::other)?
+                .iter()
+                .collect()),
+        }
+    }
+
+    /// Resolve `target` to a socket address, using the first address found
+    /// for a domain.
+    pub async fn resolve(&self, target: &TargetAddr) -> Result<SocketAddr> {
+        Ok(self.resolve_all(target).await?[0])
+    }
+
+    /// Resolve `target` to every socket address it has, never returning an
+    /// empty list.
+    pub async fn resolve_all(&self, target: &TargetAddr) -> Result<Vec<SocketAddr>> {
+        match target {
+            TargetAddr::Ip(addr) => Ok(vec![*addr]),
+            TargetAddr::Domain(host, port) => {
+                let ips = self.lookup(host).await?;
+                if ips.is_empty() {
+                    return Err(ReplyError::HostUnreachable.into());
+                }
+                debug!("Resolved {} to {:?}", host, ips);
+                Ok(ips
+                    .into_iter()
+                    .map(|ip| SocketAddr::new(ip, *port))
+                    .collect())
+            }
+        }
+    }
+}
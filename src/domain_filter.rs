@@ -0,0 +1,167 @@
+//! Domain blocklists and allowlists, read from hosts-style files such as the
+//! ad and malware lists many deployments block at the proxy.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+
+/// Names of hosts files that are not worth filtering.
+const LOCAL_NAMES: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+];
+
+/// A set of domains, stored as a trie of their labels from the top-level
+/// domain down.
+#[derive(Debug, Default)]
+pub struct DomainFilter {
+    root: Node,
+    len: usize,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    children: HashMap<Box<str>, Node>,
+    /// The name ending here is listed along with its subdomains.
+    whole: bool,
+    /// Only the subdomains of the name ending here are listed.
+    below: bool,
+}
+
+impl DomainFilter {
+    /// Read a hosts-style file: each line holds names, optionally after an
+    /// address, e.g. `0.0.0.0 ads.example.com`. A name covers its subdomains;
+    /// `*.example.com` covers only the subdomains. `#` starts a comment.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let mut filter = DomainFilter::default();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace().peekable();
+            if fields
+                .peek()
+                .is_some_and(|field| field.parse::<IpAddr>().is_ok())
+            {
+                fields.next();
+            }
+            for name in fields {
+                filter.insert(name);
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    fn insert(&mut self, name: &str) {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let (name, below) = match name.strip_prefix("*.") {
+            Some(parent) => (parent, true),
+            None => (name.trim_start_matches('.'), false),
+        };
+        if name.is_empty() || LOCAL_NAMES.contains(&name) {
+            return;
+        }
+        let mut node = &mut self.root;
+        for label in name.rsplit('.') {
+            node = node.children.entry(label.into()).or_default();
+        }
+        if below {
+            node.below = true;
+        } else {
+            node.whole = true;
+        }
+        self.len += 1;
+    }
+
+    /// Whether `domain`, lowercase and without the trailing dot, is listed.
+    pub fn contains(&self, domain: &str) -> bool {
+        let mut node = &self.root;
+        let mut labels = domain.rsplit('.').peekable();
+        while let Some(label) = labels.next() {
+            match node.children.get(label) {
+                Some(child) => node = child,
+                None => return false,
+            }
+            if node.whole || (node.below && labels.peek().is_some()) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing(names: &[&str]) -> DomainFilter {
+        let mut filter = DomainFilter::default();
+        for name in names {
+            filter.insert(name);
+        }
+        filter
+    }
+
+    #[test]
+    fn names_cover_their_subdomains() {
+        let filter = listing(&["Ads.Example.com.", ".tracker.test"]);
+        assert_eq!(filter.len(), 2);
+        assert!(filter.contains("ads.example.com"));
+        assert!(filter.contains("eu.ads.example.com"));
+        assert!(filter.contains("tracker.test"));
+        assert!(filter.contains("a.b.tracker.test"));
+        assert!(!filter.contains("example.com"));
+        assert!(!filter.contains("badads.example.com"));
+        assert!(!filter.contains("test"));
+    }
+
+    #[test]
+    fn wildcards_cover_only_subdomains() {
+        let filter = listing(&["*.example.com"]);
+        assert!(filter.contains("www.example.com"));
+        assert!(filter.contains("a.b.example.com"));
+        assert!(!filter.contains("example.com"));
+
+        let both = listing(&["*.example.com", "example.com"]);
+        assert!(both.contains("example.com"));
+        assert!(both.contains("www.example.com"));
+    }
+
+    #[test]
+    fn skips_local_and_empty_names() {
+        let filter = listing(&["localhost", "broadcasthost", ".", ""]);
+        assert_eq!(filter.len(), 0);
+        assert!(!filter.contains("localhost"));
+    }
+
+    #[test]
+    fn loads_hosts_files() {
+        let path = std::env::temp_dir().join(format!("domain-filter-{}", std::process::id()));
+        fs::write(
+            &path,
+            "# ad servers\n\
+             0.0.0.0 ads.example.com ads.example.net # both\n\
+             127.0.0.1 localhost\n\
+             ::1 ip6-localhost\n\
+             *.tracker.test\n",
+        )
+        .unwrap();
+        let filter = DomainFilter::load(&path);
+        fs::remove_file(&path).unwrap();
+        let filter = filter.unwrap();
+        assert_eq!(filter.len(), 3);
+        assert!(filter.contains("ads.example.net"));
+        assert!(filter.contains("x.tracker.test"));
+        assert!(!filter.contains("both"));
+        assert!(!filter.contains("0.0.0.0"));
+    }
+}
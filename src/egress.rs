@@ -0,0 +1,154 @@
+//! Choosing the local address and interface of outbound connections.
+
+use crate::acl::{Destination, parse_port_range};
+use crate::upstream::Upstream;
+use fast_socks5::util::target_addr::TargetAddr;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use tokio::net::TcpSocket;
+
+/// Where outbound sockets are bound before connecting, for hosts with more
+/// than one way out.
+#[derive(Debug, Clone, Copy)]
+pub struct Egress<'a> {
+    /// Local address to connect from; targets of the other family are
+    /// unreachable.
+    pub addr: Option<IpAddr>,
+    /// Network interface to connect through, with `SO_BINDTODEVICE` on
+    /// Linux. Other platforms refuse to connect when it is set.
+    pub device: Option<&'a str>,
+}
+
+impl Egress<'_> {
+    /// Whether `target` can be reached from the bound address.
+    pub fn reaches(&self, target: SocketAddr) -> bool {
+        self.addr
+            .is_none_or(|addr| addr.is_ipv4() == target.is_ipv4())
+    }
+
+    /// A socket for connecting to `target`, bound as configured.
+    pub fn socket(&self, target: SocketAddr) -> io::Result<TcpSocket> {
+        let socket = match target {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        #[cfg(target_os = "linux")]
+        if let Some(device) = self.device {
+            socket.bind_device(Some(device.as_bytes()))?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        if self.device.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "binding to a network interface is only supported on Linux",
+            ));
+        }
+        if let Some(addr) = self.addr {
+            socket.bind(SocketAddr::new(addr, 0))?;
+        }
+        Ok(socket)
+    }
+}
+
+/// The way out chosen by an egress rule, in place of `--outbound-addr` or
+/// `--upstream`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Via {
+    /// Connect directly from this local address.
+    Addr(IpAddr),
+    /// Connect directly, even when an upstream is configured.
+    Direct,
+    Upstream(Upstream),
+}
+
+impl FromStr for Via {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "direct" {
+            return Ok(Via::Direct);
+        }
+        if s.contains("://") {
+            return s.parse().map(Via::Upstream);
+        }
+        s.parse()
+            .map(Via::Addr)
+            .map_err(|_| format!("invalid egress `{}`", s))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Selector {
+    User(String),
+    Destination(Destination, Option<(u16, u16)>),
+}
+
+/// An egress rule: `user:<name> <via>` or `<destination> [port[-port]]
+/// <via>`.
+///
+/// The destination takes the forms of ACL rules. `via` is a local address
+/// to connect from, an upstream proxy URL, or `direct`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EgressRule {
+    selector: Selector,
+    via: Via,
+}
+
+impl FromStr for EgressRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<_> = s.split_whitespace().collect();
+        let (selector, via) = match fields[..] {
+            [user, via] if user.starts_with("user:") => {
+                (Selector::User(user["user:".len()..].to_string()), via)
+            }
+            [destination, via] => (Selector::Destination(destination.parse()?, None), via),
+            [destination, ports, via] => (
+                Selector::Destination(destination.parse()?, Some(parse_port_range(ports)?)),
+                via,
+            ),
+            _ => {
+                return Err(format!(
+                    "egress rule `{}` should be `user:<name> <via>` or `<destination> [ports] <via>`",
+                    s
+                ));
+            }
+        };
+        Ok(EgressRule {
+            selector,
+            via: via.parse()?,
+        })
+    }
+}
+
+/// The way out of the first rule matching `user` and `target`, if any.
+///
+/// Without a target, e.g. for UDP associations, only user rules match.
+pub fn select<'a>(
+    rules: &'a [EgressRule],
+    user: Option<&str>,
+    target: Option<&TargetAddr>,
+) -> Option<&'a Via> {
+    let target = target.map(|target| match target {
+        TargetAddr::Ip(addr) => (None, Some(addr.ip()), addr.port()),
+        TargetAddr::Domain(name, port) => (
+            Some(name.trim_end_matches('.').to_ascii_lowercase()),
+            None,
+            *port,
+        ),
+    });
+    rules
+        .iter()
+        .find(|rule| match &rule.selector {
+            Selector::User(name) => user == Some(name.as_str()),
+            Selector::Destination(destination, ports) => {
+                target.as_ref().is_some_and(|(domain, ip, port)| {
+                    ports.is_none_or(|(first, last)| (first..=last).contains(port))
+                        && destination.matches(domain.as_deref(), *ip)
+                })
+            }
+        })
+        .map(|rule| &rule.via)
+}
@@ -0,0 +1,120 @@
+//! Destination policy by country and autonomous system, looked up in
+//! MaxMind-format databases (GeoLite2 or GeoIP2). Built with the `geoip`
+//! feature.
+
+use crate::acl::Action;
+use maxminddb::{Reader, geoip2};
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Where a destination address is.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Location {
+    /// ISO 3166-1 code, e.g. `DE`.
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Matcher {
+    Country(String),
+    Asn(u32),
+}
+
+/// A GeoIP rule: `<allow|deny> country:<code>` or `<allow|deny> asn:<number>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeoRule {
+    action: Action,
+    matcher: Matcher,
+}
+
+impl GeoRule {
+    fn matches(&self, location: &Location) -> bool {
+        match &self.matcher {
+            Matcher::Country(code) => location.country.as_deref() == Some(code.as_str()),
+            Matcher::Asn(asn) => location.asn == Some(*asn),
+        }
+    }
+}
+
+impl FromStr for GeoRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "GeoIP rule `{}` should be `<allow|deny> country:<code>|asn:<number>`",
+                s
+            )
+        };
+        let (action, what) = s.trim().split_once(' ').ok_or_else(invalid)?;
+        let matcher = match what.trim().split_once(':') {
+            Some(("country", code)) if code.len() == 2 => {
+                Matcher::Country(code.to_ascii_uppercase())
+            }
+            Some(("asn", asn)) => Matcher::Asn(
+                asn.trim_start_matches("AS")
+                    .parse()
+                    .map_err(|_| invalid())?,
+            ),
+            _ => return Err(invalid()),
+        };
+        Ok(GeoRule {
+            action: action.parse()?,
+            matcher,
+        })
+    }
+}
+
+/// The GeoIP databases and the rules applied to what they say.
+pub struct GeoIp {
+    country: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+    rules: Vec<GeoRule>,
+}
+
+impl GeoIp {
+    pub fn open(
+        country_db: Option<&Path>,
+        asn_db: Option<&Path>,
+        rules: Vec<GeoRule>,
+    ) -> io::Result<Self> {
+        let open = |path: &Path| {
+            Reader::open_readfile(path).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("can't open GeoIP database {}: {}", path.display(), err),
+                )
+            })
+        };
+        Ok(GeoIp {
+            country: country_db.map(open).transpose()?,
+            asn: asn_db.map(open).transpose()?,
+            rules,
+        })
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Location {
+        let ip = ip.to_canonical();
+        let country = self.country.as_ref().and_then(|reader| {
+            let record: geoip2::Country = reader.lookup(ip).ok()?;
+            Some(record.country?.iso_code?.to_string())
+        });
+        let asn = self.asn.as_ref().and_then(|reader| {
+            let record: geoip2::Asn = reader.lookup(ip).ok()?;
+            record.autonomous_system_number
+        });
+        Location { country, asn }
+    }
+
+    /// The first matching rule decides; locations no rule matches are
+    /// allowed, leaving them to the ACL.
+    pub fn allows(&self, location: &Location) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(location))
+            .is_none_or(|rule| rule.action == Action::Allow)
+    }
+}
@@ -0,0 +1,123 @@
+//! Refusing destinations inside the proxy host's own networks.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Checks the address an outbound connection is about to be made to.
+///
+/// The check runs on the resolved address right before connecting, so a
+/// domain that resolves to an internal address is refused as well.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DestinationGuard {
+    block_private: bool,
+}
+
+impl DestinationGuard {
+    /// A guard refusing loopback, link-local, private (RFC 1918), shared
+    /// (RFC 6598, used by carrier-grade NAT), "this network" (0.0.0.0/8) and
+    /// unique local (RFC 4193) destinations when `block_private` is set, also
+    /// behind NAT64 and 6to4 addresses.
+    pub fn new(block_private: bool) -> Self {
+        DestinationGuard { block_private }
+    }
+
+    pub fn allows(&self, addr: SocketAddr) -> bool {
+        !(self.block_private && is_internal(addr.ip()))
+    }
+}
+
+fn is_internal(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => is_internal_v4(ip),
+        IpAddr::V6(ip) => {
+            ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_unicast_link_local()
+                || ip.is_unique_local()
+                || embedded_ipv4(ip).is_some_and(is_internal_v4)
+        }
+    }
+}
+
+fn is_internal_v4(ip: Ipv4Addr) -> bool {
+    // 0.0.0.0/8 is "this network", which Linux connects to as the host.
+    ip.octets()[0] == 0
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_private()
+        || is_shared(ip)
+        || ip.is_broadcast()
+}
+
+/// The IPv4 address inside a NAT64 (64:ff9b::/96, RFC 6052) or 6to4
+/// (2002::/16, RFC 3056) address, which reaches that IPv4 host.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let octets = ip.octets();
+    match ip.segments() {
+        [0x64, 0xff9b, 0, 0, 0, 0, ..] => Some(Ipv4Addr::new(
+            octets[12], octets[13], octets[14], octets[15],
+        )),
+        [0x2002, ..] => Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5])),
+        _ => None,
+    }
+}
+
+/// Whether `ip` is in the shared address space 100.64.0.0/10.
+fn is_shared(ip: Ipv4Addr) -> bool {
+    let [first, second, ..] = ip.octets();
+    first == 100 && second & 0xc0 == 64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn internal(ip: &str) -> bool {
+        is_internal(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn refuses_internal_ipv4() {
+        for ip in [
+            "0.0.0.0",
+            "0.1.2.3",
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "100.127.255.255",
+            "255.255.255.255",
+        ] {
+            assert!(internal(ip), "{}", ip);
+        }
+        for ip in ["1.1.1.1", "100.128.0.1", "172.32.0.1", "192.0.2.1"] {
+            assert!(!internal(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn refuses_internal_ipv6() {
+        for ip in ["::", "::1", "fe80::1", "fd00::1", "::ffff:127.0.0.1"] {
+            assert!(internal(ip), "{}", ip);
+        }
+        assert!(!internal("2001:db8::1"));
+    }
+
+    #[test]
+    fn unwraps_nat64_and_6to4() {
+        for ip in [
+            "64:ff9b::7f00:1",
+            "64:ff9b::10.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "2002:7f00:1::",
+            "2002:c0a8:101::1",
+            "2002:0:1::1",
+        ] {
+            assert!(internal(ip), "{}", ip);
+        }
+        for ip in ["64:ff9b::1.1.1.1", "2002:101:101::1", "64:ff9b:1::7f00:1"] {
+            assert!(!internal(ip), "{}", ip);
+        }
+    }
+}
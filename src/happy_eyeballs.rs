@@ -0,0 +1,187 @@
+//! Connecting to targets with several addresses, racing the attempts as
+//! described by Happy Eyeballs (RFC 8305).
+
+use crate::egress::Egress;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tokio::time::{Instant, sleep};
+use tracing::Instrument as _;
+
+/// The address family to try first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FamilyPreference {
+    Ipv6,
+    Ipv4,
+}
+
+impl FromStr for FamilyPreference {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ipv6" => Ok(FamilyPreference::Ipv6),
+            "ipv4" => Ok(FamilyPreference::Ipv4),
+            _ => Err(format!("unknown address family `{}`", s)),
+        }
+    }
+}
+
+/// Order `addrs` for connecting, alternating between the two families and
+/// starting with the preferred one.
+pub fn interleave(addrs: Vec<SocketAddr>, prefer: FamilyPreference) -> Vec<SocketAddr> {
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == (prefer == FamilyPreference::Ipv6));
+    let mut ordered = Vec::with_capacity(preferred.len() + other.len());
+    let (mut preferred, mut other) = (preferred.into_iter(), other.into_iter());
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return ordered,
+            (first, second) => ordered.extend(first.into_iter().chain(second)),
+        }
+    }
+}
+
+/// Connect to the first of `addrs` to accept, in order, from `egress`.
+///
+/// A new attempt starts whenever one fails or the last one started has been
+/// pending for `attempt_delay` (RFC 8305, section 5); the attempts still
+/// pending when one succeeds are dropped.
+pub async fn connect(
+    addrs: &[SocketAddr],
+    attempt_delay: Duration,
+    egress: &Egress<'_>,
+) -> io::Result<TcpStream> {
+    let mut remaining = addrs.iter().copied();
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+    // Reset only when an attempt starts, so that it runs for the whole delay.
+    let delay = sleep(attempt_delay);
+    tokio::pin!(delay);
+    // Every turn but the first follows a failure or the delay running out,
+    // both of which start the next attempt.
+    loop {
+        match remaining.next() {
+            Some(addr) => {
+                attempts.spawn(attempt(egress, addr));
+                delay.as_mut().reset(Instant::now() + attempt_delay);
+            }
+            None if attempts.is_empty() => {
+                return Err(last_error.unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to")
+                }));
+            }
+            None => {}
+        }
+
+        tokio::select! {
+            Some(result) = attempts.join_next() => match result.map_err(io::Error::other) {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(err)) | Err(err) => last_error = Some(err),
+            },
+            () = &mut delay, if remaining.len() > 0 => {}
+        }
+    }
+}
+
+fn attempt(
+    egress: &Egress<'_>,
+    addr: SocketAddr,
+) -> impl Future<Output = io::Result<TcpStream>> + use<> {
+    let socket = egress.socket(addr);
+    async move {
+        socket?
+            .connect(addr)
+            .await
+            .inspect_err(|err| debug!("Connecting to {} failed: {}", addr, err))
+    }
+    .in_current_span()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::time::timeout;
+
+    const NO_EGRESS: Egress<'static> = Egress {
+        addr: None,
+        device: None,
+    };
+
+    #[test]
+    fn interleaves_families() {
+        let addrs: Vec<SocketAddr> = ["10.0.0.1:80", "10.0.0.2:80", "[::1]:80", "10.0.0.3:80"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let ordered = interleave(addrs.clone(), FamilyPreference::Ipv6);
+        assert_eq!(ordered, [addrs[2], addrs[0], addrs[1], addrs[3]]);
+        let ordered = interleave(addrs.clone(), FamilyPreference::Ipv4);
+        assert_eq!(ordered, [addrs[0], addrs[2], addrs[1], addrs[3]]);
+    }
+
+    #[tokio::test]
+    async fn starts_next_attempt_on_failure() {
+        let refused = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let refused_addr = refused.local_addr().unwrap();
+        drop(refused);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addrs = [refused_addr, listener.local_addr().unwrap()];
+
+        let connecting = connect(&addrs, Duration::from_secs(60), &NO_EGRESS);
+        let stream = timeout(Duration::from_secs(5), connecting).await;
+        assert_eq!(
+            stream.unwrap().unwrap().peer_addr().unwrap(),
+            listener.local_addr().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn starts_next_attempt_on_failure_while_others_pend() {
+        // With its one-connection backlog full, the listener drops SYNs.
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let unresponsive = socket.listen(0).unwrap();
+        let unresponsive_addr = unresponsive.local_addr().unwrap();
+        let _queued = TcpStream::connect(unresponsive_addr).await.unwrap();
+        let refused = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let refused_addr = refused.local_addr().unwrap();
+        drop(refused);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addrs = [
+            unresponsive_addr,
+            refused_addr,
+            listener.local_addr().unwrap(),
+        ];
+
+        // The third attempt starts when the second fails, one delay in,
+        // rather than a delay after that.
+        let delay = Duration::from_secs(1);
+        let connecting = connect(&addrs, delay, &NO_EGRESS);
+        let stream = timeout(delay * 3 / 2, connecting).await;
+        assert_eq!(
+            stream.unwrap().unwrap().peer_addr().unwrap(),
+            listener.local_addr().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn returns_last_error() {
+        let refused = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = refused.local_addr().unwrap();
+        drop(refused);
+        let err = connect(&[addr, addr], Duration::from_secs(60), &NO_EGRESS)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        let err = connect(&[], Duration::from_secs(60), &NO_EGRESS)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,52 @@
+//! Caps on the concurrent sessions of each client address.
+
+use crate::client_stream::Source;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Counts the sessions of each client source, up to `max` at once.
+pub struct IpLimit {
+    max: usize,
+    sessions: Mutex<HashMap<Source, usize>>,
+}
+
+impl IpLimit {
+    pub fn new(max: usize) -> Self {
+        IpLimit {
+            max,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a new session of `source`, unless it already has `max` of them.
+    pub fn try_acquire(&'static self, source: Source) -> Option<IpPermit> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let count = sessions.entry(source).or_insert(0);
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(IpPermit {
+            limit: self,
+            source,
+        })
+    }
+}
+
+/// One session of a source, counted until dropped.
+pub struct IpPermit {
+    limit: &'static IpLimit,
+    source: Source,
+}
+
+impl Drop for IpPermit {
+    fn drop(&mut self) {
+        let mut sessions = self.limit.sessions.lock().unwrap();
+        if let Some(count) = sessions.get_mut(&self.source) {
+            *count -= 1;
+            if *count == 0 {
+                sessions.remove(&self.source);
+            }
+        }
+    }
+}
@@ -0,0 +1,212 @@
+//! The addresses the server listens on, each of which may override the
+//! global auth mode, ACL, UDP flag and TLS, and take clients over WebSocket.
+
+use crate::acl::{Action, Rule};
+use crate::client_stream::{self, ClientStream};
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::str::FromStr;
+#[cfg(feature = "quic")]
+use std::sync::Arc;
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+#[cfg(feature = "quic")]
+use tokio::sync::mpsc;
+
+/// A listen address, given as `addr[,setting...]` with the settings
+/// `auth=none|peer`, `udp=on|off`, `acl=<rule>` (repeatable),
+/// `acl-default=allow|deny`, `tls=on|off` and `ws=on|off`. An address of
+/// `unix:<path>` is a Unix socket, and one of `quic:<addr>` takes clients
+/// over QUIC, always with TLS.
+#[derive(Debug, Clone, Default)]
+pub struct ListenerSpec {
+    pub addr: String,
+    /// Let clients in without authentication, whatever the auth mode.
+    pub no_auth: bool,
+    /// Let clients of a Unix socket in as the user they run as, whatever
+    /// the auth mode.
+    pub peer_auth: bool,
+    pub allow_udp: Option<bool>,
+    /// Replaces the global ACL rules when not empty.
+    pub acl: Vec<Rule>,
+    pub acl_default: Option<Action>,
+    /// Whether clients connect over TLS, by default when a certificate is
+    /// given, except on Unix sockets.
+    pub tls: Option<bool>,
+    /// Clients connect over WebSocket, inside TLS when the listener uses it.
+    pub websocket: bool,
+}
+
+impl ListenerSpec {
+    /// A listener using the global settings.
+    pub fn plain(addr: String) -> Self {
+        ListenerSpec {
+            addr,
+            ..ListenerSpec::default()
+        }
+    }
+
+    /// The path of a Unix socket listener.
+    pub fn unix_path(&self) -> Option<&Path> {
+        self.addr.strip_prefix("unix:").map(Path::new)
+    }
+
+    /// The UDP address of a QUIC listener.
+    pub fn quic_addr(&self) -> Option<&str> {
+        self.addr.strip_prefix("quic:")
+    }
+}
+
+impl FromStr for ListenerSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut settings = s.split(',');
+        let addr = settings.next().unwrap_or_default().trim();
+        if addr.is_empty() {
+            return Err(format!("listener `{}` has no address", s));
+        }
+        let mut spec = ListenerSpec::plain(addr.to_string());
+        for setting in settings {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("listener setting `{}` has no value", setting))?;
+            match (key.trim(), value.trim()) {
+                ("auth", "none") => (spec.no_auth, spec.peer_auth) = (true, false),
+                ("auth", "peer") if spec.unix_path().is_some() => {
+                    (spec.no_auth, spec.peer_auth) = (false, true)
+                }
+                ("auth", "peer") => {
+                    return Err(format!(
+                        "listener `{}` isn't a Unix socket for auth=peer",
+                        addr
+                    ));
+                }
+                ("auth", "password") => (spec.no_auth, spec.peer_auth) = (false, false),
+                ("udp", "on") => spec.allow_udp = Some(true),
+                ("udp", "off") => spec.allow_udp = Some(false),
+                ("acl", rule) => spec.acl.push(rule.parse()?),
+                ("acl-default", action) => spec.acl_default = Some(action.parse()?),
+                ("tls", "on") if spec.unix_path().is_some() => {
+                    return Err(format!(
+                        "listener `{}` is a Unix socket, it can't use TLS",
+                        addr
+                    ));
+                }
+                ("tls", _) if spec.quic_addr().is_some() => {
+                    return Err(format!(
+                        "listener `{}` is QUIC, which always uses TLS",
+                        addr
+                    ));
+                }
+                ("tls", "on") => spec.tls = Some(true),
+                ("tls", "off") => spec.tls = Some(false),
+                ("ws", "on") => spec.websocket = true,
+                ("ws", "off") => spec.websocket = false,
+                _ => return Err(format!("unknown listener setting `{}`", setting)),
+            }
+        }
+        Ok(spec)
+    }
+}
+
+/// A bound listener. A Unix socket listener removes its socket file when
+/// dropped.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+    /// With the streams its clients open, queued by [`crate::quic::accept`].
+    #[cfg(feature = "quic")]
+    Quic(
+        quinn::Endpoint,
+        tokio::sync::Mutex<mpsc::Receiver<(ClientStream, SocketAddr)>>,
+    ),
+}
+
+impl Listener {
+    /// Bind `spec`, replacing a socket left behind at the path of a Unix
+    /// socket listener.
+    pub async fn bind(spec: &ListenerSpec) -> io::Result<Self> {
+        match spec.unix_path() {
+            Some(path) => bind_unix(path),
+            None => Ok(Listener::Tcp(TcpListener::bind(&spec.addr).await?)),
+        }
+    }
+
+    /// Bind the QUIC listener of `spec`, terminating TLS with `tls`.
+    #[cfg(feature = "quic")]
+    pub fn bind_quic(
+        spec: &ListenerSpec,
+        tls: Arc<tokio_rustls::rustls::ServerConfig>,
+    ) -> io::Result<Self> {
+        let addr = spec.quic_addr().unwrap_or(&spec.addr);
+        let endpoint = crate::quic::listen(addr, tls)?;
+        let (streams, queued) = mpsc::channel(1);
+        tokio::spawn(crate::quic::accept(endpoint.clone(), streams));
+        Ok(Listener::Quic(endpoint, tokio::sync::Mutex::new(queued)))
+    }
+
+    /// Accept a client, with its address or [`client_stream::LOCAL`] for a
+    /// Unix socket client.
+    pub async fn accept(&self) -> io::Result<(ClientStream, SocketAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (socket, client_addr) = listener.accept().await?;
+                Ok((ClientStream::tcp(socket), client_addr))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (socket, _) = listener.accept().await?;
+                Ok((ClientStream::unix(socket), client_stream::LOCAL))
+            }
+            #[cfg(feature = "quic")]
+            Listener::Quic(_, queued) => {
+                queued.lock().await.recv().await.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotConnected, "QUIC endpoint closed")
+                })
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> io::Result<Listener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        _ => {}
+    }
+    Ok(Listener::Unix(
+        UnixListener::bind(path)?,
+        path.to_path_buf(),
+    ))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &Path) -> io::Result<Listener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix socket listeners are not supported on this platform",
+    ))
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = self {
+            std::fs::remove_file(&*path).unwrap_or_else(|err| {
+                warn!("Can't remove socket {}: {}", path.display(), err);
+            });
+        }
+        #[cfg(feature = "quic")]
+        if let Listener::Quic(endpoint, _) = self {
+            endpoint.close(0u32.into(), b"shutting down");
+        }
+    }
+}
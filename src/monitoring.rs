@@ -0,0 +1,112 @@
+//! Prometheus metrics, exported when built with the `metrics` feature.
+//!
+//! Without the feature every recorder in here is a no-op.
+
+pub use imp::*;
+
+#[cfg(feature = "metrics")]
+mod imp {
+    use metrics::{counter, gauge, histogram};
+    use metrics_exporter_prometheus::{BuildError, PrometheusBuilder, PrometheusHandle};
+    use std::net::SocketAddr;
+    use std::sync::OnceLock;
+    use std::time::Duration;
+
+    static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+    /// Install the global recorder, serving `/metrics` on `addr` if given.
+    pub fn install(addr: Option<SocketAddr>) -> Result<(), BuildError> {
+        let handle = match addr {
+            Some(addr) => {
+                let (recorder, exporter) =
+                    PrometheusBuilder::new().with_http_listener(addr).build()?;
+                let handle = recorder.handle();
+                metrics::set_global_recorder(recorder)?;
+                tokio::spawn(exporter);
+                handle
+            }
+            None => PrometheusBuilder::new().install_recorder()?,
+        };
+        let _ = HANDLE.set(handle);
+        Ok(())
+    }
+
+    /// The metrics in the Prometheus text format, once installed.
+    #[cfg(feature = "admin")]
+    pub fn render() -> Option<String> {
+        HANDLE.get().map(PrometheusHandle::render)
+    }
+
+    /// Counts a client session as active for as long as it is alive.
+    pub struct ActiveSession(());
+
+    impl ActiveSession {
+        pub fn start() -> Self {
+            gauge!("socks_active_sessions").increment(1.0);
+            ActiveSession(())
+        }
+    }
+
+    impl Drop for ActiveSession {
+        fn drop(&mut self) {
+            gauge!("socks_active_sessions").decrement(1.0);
+        }
+    }
+
+    pub fn handshake_failed(reason: &'static str) {
+        counter!("socks_handshake_failures_total", "reason" => reason).increment(1);
+    }
+
+    pub fn connect_latency(elapsed: Duration) {
+        histogram!("socks_connect_duration_seconds").record(elapsed.as_secs_f64());
+    }
+
+    pub fn bytes_relayed(up: u64, down: u64) {
+        counter!("socks_relayed_bytes_total", "direction" => "up").increment(up);
+        counter!("socks_relayed_bytes_total", "direction" => "down").increment(down);
+    }
+
+    pub fn datagram_relayed(direction: &'static str, size: usize) {
+        counter!("socks_udp_datagrams_total", "direction" => direction).increment(1);
+        counter!("socks_udp_bytes_total", "direction" => direction).increment(size as u64);
+    }
+
+    pub fn datagram_dropped(direction: &'static str, reason: &'static str) {
+        counter!("socks_udp_datagrams_dropped_total", "direction" => direction, "reason" => reason)
+            .increment(1);
+    }
+
+    pub fn icmp_error(kind: &'static str) {
+        counter!("socks_udp_icmp_errors_total", "kind" => kind).increment(1);
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod imp {
+    use std::time::Duration;
+
+    pub struct ActiveSession(());
+
+    impl ActiveSession {
+        pub fn start() -> Self {
+            ActiveSession(())
+        }
+    }
+
+    #[cfg(feature = "admin")]
+    pub fn render() -> Option<String> {
+        None
+    }
+
+    pub fn handshake_failed(_reason: &'static str) {}
+
+    pub fn connect_latency(_elapsed: Duration) {}
+
+    pub fn bytes_relayed(_up: u64, _down: u64) {}
+
+    pub fn datagram_relayed(_direction: &'static str, _size: usize) {}
+
+    pub fn datagram_dropped(_direction: &'static str, _reason: &'static str) {}
+
+    pub fn icmp_error(_kind: &'static str) {}
+}
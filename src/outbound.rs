@@ -0,0 +1,141 @@
+//! The connection to a target, made over TCP or, through an upstream proxy
+//! listening on a Unix socket or taking clients over QUIC or WebSocket, over
+//! that socket, a QUIC stream or a WebSocket.
+
+#[cfg(unix)]
+use crate::client_stream::LOCAL;
+use std::io::{self, IoSlice};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+/// An outbound connection.
+pub enum Outbound {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(feature = "websocket")]
+    WebSocket(Box<crate::websocket::WebSocket<Outbound>>),
+    #[cfg(feature = "quic")]
+    Quic(Box<crate::quic::QuicStream>),
+}
+
+impl Outbound {
+    /// The local address of the connection, [`LOCAL`] on a Unix socket.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Outbound::Tcp(socket) => socket.local_addr(),
+            #[cfg(unix)]
+            Outbound::Unix(_) => Ok(LOCAL),
+            #[cfg(feature = "websocket")]
+            Outbound::WebSocket(stream) => stream.get_ref().local_addr(),
+            #[cfg(feature = "quic")]
+            Outbound::Quic(stream) => Ok(stream.local_addr()),
+        }
+    }
+
+    /// The address connected to, [`LOCAL`] on a Unix socket.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Outbound::Tcp(socket) => socket.peer_addr(),
+            #[cfg(unix)]
+            Outbound::Unix(_) => Ok(LOCAL),
+            #[cfg(feature = "websocket")]
+            Outbound::WebSocket(stream) => stream.get_ref().peer_addr(),
+            #[cfg(feature = "quic")]
+            Outbound::Quic(stream) => Ok(stream.peer_addr()),
+        }
+    }
+}
+
+impl AsyncRead for Outbound {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Outbound::Tcp(socket) => Pin::new(socket).poll_read(cx, buf),
+            #[cfg(unix)]
+            Outbound::Unix(socket) => Pin::new(socket).poll_read(cx, buf),
+            #[cfg(feature = "websocket")]
+            Outbound::WebSocket(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "quic")]
+            Outbound::Quic(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Outbound {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Outbound::Tcp(socket) => Pin::new(socket).poll_write(cx, buf),
+            #[cfg(unix)]
+            Outbound::Unix(socket) => Pin::new(socket).poll_write(cx, buf),
+            #[cfg(feature = "websocket")]
+            Outbound::WebSocket(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "quic")]
+            Outbound::Quic(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Outbound::Tcp(socket) => Pin::new(socket).poll_write_vectored(cx, bufs),
+            #[cfg(unix)]
+            Outbound::Unix(socket) => Pin::new(socket).poll_write_vectored(cx, bufs),
+            #[cfg(feature = "websocket")]
+            Outbound::WebSocket(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            #[cfg(feature = "quic")]
+            Outbound::Quic(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Outbound::Tcp(socket) => socket.is_write_vectored(),
+            #[cfg(unix)]
+            Outbound::Unix(socket) => socket.is_write_vectored(),
+            #[cfg(feature = "websocket")]
+            Outbound::WebSocket(stream) => stream.is_write_vectored(),
+            #[cfg(feature = "quic")]
+            Outbound::Quic(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Outbound::Tcp(socket) => Pin::new(socket).poll_flush(cx),
+            #[cfg(unix)]
+            Outbound::Unix(socket) => Pin::new(socket).poll_flush(cx),
+            #[cfg(feature = "websocket")]
+            Outbound::WebSocket(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "quic")]
+            Outbound::Quic(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Outbound::Tcp(socket) => Pin::new(socket).poll_shutdown(cx),
+            #[cfg(unix)]
+            Outbound::Unix(socket) => Pin::new(socket).poll_shutdown(cx),
+            #[cfg(feature = "websocket")]
+            Outbound::WebSocket(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "quic")]
+            Outbound::Quic(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,262 @@
+//! The HAProxy PROXY protocol, versions 1 and 2, with which load balancers
+//! pass on the address of the client they accepted the connection from.
+//!
+//! The server reads it from load balancers in front of it, and can send
+//! version 2 headers to backends behind it.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Starts every version 2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// A version 1 header is a single line of at most this many bytes, CRLF
+/// included.
+const V1_MAX_LEN: usize = 107;
+
+const V2_VERSION: u8 = 0x20;
+const V2_CMD_LOCAL: u8 = 0x00;
+const V2_CMD_PROXY: u8 = 0x01;
+const V2_FAMILY_TCP4: u8 = 0x11;
+const V2_FAMILY_TCP6: u8 = 0x21;
+
+/// Read the PROXY header at the start of `stream`, returning the client
+/// address it carries. `None` means the header holds no address, for the
+/// load balancer's own health checks, so the connection's peer stands.
+///
+/// Nothing past the header is read.
+pub async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    // Both versions are longer than the version 2 signature.
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        Err(invalid("connection doesn't start with a PROXY header"))
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    start: &[u8],
+) -> io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            return Err(invalid("PROXY header line is too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY header is not ASCII"))?;
+
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        [
+            "PROXY",
+            family @ ("TCP4" | "TCP6"),
+            src_ip,
+            _dst_ip,
+            src_port,
+            _dst_port,
+        ] => {
+            let ip: IpAddr = src_ip
+                .parse()
+                .map_err(|_| invalid("invalid source address in PROXY header"))?;
+            if ip.is_ipv4() != (family == "TCP4") {
+                return Err(invalid("PROXY header address doesn't match its family"));
+            }
+            let port = src_port
+                .parse()
+                .map_err(|_| invalid("invalid source port in PROXY header"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY header")),
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    let [version_command, family, len_hi, len_lo] = header;
+    if version_command & 0xf0 != V2_VERSION {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    // The address block, possibly followed by TLVs, which are skipped.
+    let mut block = vec![0u8; usize::from(u16::from_be_bytes([len_hi, len_lo]))];
+    stream.read_exact(&mut block).await?;
+
+    match version_command & 0x0f {
+        V2_CMD_LOCAL => return Ok(None),
+        V2_CMD_PROXY => {}
+        _ => return Err(invalid("unknown PROXY header command")),
+    }
+    match family {
+        V2_FAMILY_TCP4 if block.len() >= 12 => {
+            let ip = Ipv4Addr::new(block[0], block[1], block[2], block[3]);
+            let port = u16::from_be_bytes([block[8], block[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        V2_FAMILY_TCP6 if block.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&block[0..16]).unwrap());
+            let port = u16::from_be_bytes([block[32], block[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        V2_FAMILY_TCP4 | V2_FAMILY_TCP6 => Err(invalid("truncated PROXY header addresses")),
+        // UDP and Unix socket sources say nothing about a TCP client.
+        _ => Ok(None),
+    }
+}
+
+/// A version 2 header announcing a TCP connection from `source` to
+/// `destination`, both as IPv6 addresses when their families differ.
+pub fn encode_v2(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    header.push(V2_VERSION | V2_CMD_PROXY);
+    match (source.ip().to_canonical(), destination.ip().to_canonical()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            header.push(V2_FAMILY_TCP4);
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+        }
+        (src, dst) => {
+            header.push(V2_FAMILY_TCP6);
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&to_ipv6(src).octets());
+            header.extend_from_slice(&to_ipv6(dst).octets());
+        }
+    }
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header.extend_from_slice(&destination.port().to_be_bytes());
+    header
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut bytes: &[u8]) -> io::Result<Option<SocketAddr>> {
+        read_header(&mut bytes).await
+    }
+
+    #[tokio::test]
+    async fn reads_v1_examples() {
+        assert_eq!(
+            read(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n")
+                .await
+                .unwrap(),
+            Some("192.168.0.1:56324".parse().unwrap())
+        );
+        assert_eq!(
+            read(b"PROXY TCP6 2001:db8::1 2001:db8::2 65535 1080\r\n")
+                .await
+                .unwrap(),
+            Some("[2001:db8::1]:65535".parse().unwrap())
+        );
+        let longest = b"PROXY UNKNOWN ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff \
+            ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff 65535 65535\r\n";
+        assert_eq!(longest.len(), V1_MAX_LEN);
+        assert_eq!(read(longest).await.unwrap(), None);
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_v1() {
+        for bytes in [
+            &b"GET / HTTP/1.1\r\n"[..],
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324\r\n",
+            b"PROXY TCP4 ::1 ::1 56324 443\r\n",
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 65536 443\r\n",
+        ] {
+            let err = read(bytes).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        let mut long = b"PROXY UNKNOWN ".to_vec();
+        long.resize(V1_MAX_LEN + 10, b'f');
+        long.extend_from_slice(b"\r\n");
+        let err = read(&long).await.unwrap_err();
+        assert_eq!(err.to_string(), "PROXY header line is too long");
+    }
+
+    #[tokio::test]
+    async fn reads_only_the_header() {
+        let mut bytes = &b"PROXY UNKNOWN\r\n\x05\x01\x00"[..];
+        read_header(&mut bytes).await.unwrap();
+        assert_eq!(bytes, b"\x05\x01\x00");
+
+        let mut header = encode_v2(
+            "10.0.0.1:1234".parse().unwrap(),
+            "10.0.0.2:1080".parse().unwrap(),
+        );
+        header.extend_from_slice(b"\x05\x01\x00");
+        let mut bytes = &header[..];
+        read_header(&mut bytes).await.unwrap();
+        assert_eq!(bytes, b"\x05\x01\x00");
+    }
+
+    #[tokio::test]
+    async fn reads_v2_proxy_and_local() {
+        // PROXY TCP4 with a NOOP TLV after the addresses.
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(b"\x21\x11\x00\x10");
+        header.extend_from_slice(b"\xc0\xa8\x00\x01\xc0\xa8\x00\x0b\xdc\x04\x01\xbb");
+        header.extend_from_slice(b"\x04\x00\x01\x00");
+        assert_eq!(
+            read(&header).await.unwrap(),
+            Some("192.168.0.1:56324".parse().unwrap())
+        );
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(b"\x20\x00\x00\x00");
+        assert_eq!(read(&local).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_v2() {
+        let mut version = V2_SIGNATURE.to_vec();
+        version.extend_from_slice(b"\x11\x11\x00\x00");
+        let mut short = V2_SIGNATURE.to_vec();
+        short.extend_from_slice(b"\x21\x11\x00\x04\x7f\x00\x00\x01");
+        for bytes in [version, short] {
+            let err = read(&bytes).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        let mut truncated = V2_SIGNATURE.to_vec();
+        truncated.extend_from_slice(b"\x21\x11\x00\x0c\x7f\x00");
+        let err = read(&truncated).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn encodes_v2() {
+        let source: SocketAddr = "192.168.0.1:56324".parse().unwrap();
+        let destination: SocketAddr = "192.168.0.11:443".parse().unwrap();
+        let header = encode_v2(source, destination);
+        assert_eq!(header.len(), 28);
+        assert_eq!(&header[12..16], b"\x21\x11\x00\x0c");
+        assert_eq!(read(&header).await.unwrap(), Some(source));
+
+        let source: SocketAddr = "[2001:db8::1]:56324".parse().unwrap();
+        let header = encode_v2(source, destination);
+        assert_eq!(header.len(), 52);
+        assert_eq!(&header[12..16], b"\x21\x21\x00\x24");
+        assert_eq!(read(&header).await.unwrap(), Some(source));
+    }
+}
@@ -0,0 +1,122 @@
+//! Finding the address sent to clients in UDP ASSOCIATE and BIND replies.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::time::sleep;
+
+/// Where the address UDP clients send datagrams to comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplySource {
+    /// `--public-addr`, or the address found by `--public-addr-command`.
+    Public,
+    /// The local address of the client's control connection.
+    Listener,
+    /// The address outbound traffic leaves from: `--outbound-addr`, or the
+    /// one this host sends to the client from.
+    Outbound,
+}
+
+impl FromStr for ReplySource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "public" => Ok(ReplySource::Public),
+            "listener" => Ok(ReplySource::Listener),
+            "outbound" => Ok(ReplySource::Outbound),
+            _ => Err(format!("unknown reply address source `{}`", s)),
+        }
+    }
+}
+
+/// A public address found by an external command, e.g. a STUN client or
+/// `curl -s https://ifconfig.me`, which must print the address and nothing
+/// else.
+pub struct Discovery {
+    command: String,
+    current: RwLock<Option<IpAddr>>,
+}
+
+impl Discovery {
+    pub fn new(command: String) -> Self {
+        Discovery {
+            command,
+            current: RwLock::new(None),
+        }
+    }
+
+    /// The last address found, if the command succeeded once.
+    pub fn get(&self) -> Option<IpAddr> {
+        *self.current.read().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Run the command now and then every `period`, keeping the last address
+    /// found when it fails.
+    pub async fn refresh_every(&self, period: Duration) {
+        loop {
+            match self.run().await {
+                Ok(ip) => {
+                    let mut current = self.current.write().unwrap_or_else(|err| err.into_inner());
+                    if *current != Some(ip) {
+                        info!("Public address is now {}", ip);
+                        *current = Some(ip);
+                    }
+                }
+                Err(err) => warn!("Can't discover the public address: {}", err),
+            }
+            sleep(period).await;
+        }
+    }
+
+    async fn run(&self) -> io::Result<IpAddr> {
+        let output = shell(&self.command).output().await?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "`{}` exited with {}",
+                self.command, output.status
+            )));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        stdout.trim().parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "`{}` printed `{}`, not an address",
+                    self.command,
+                    stdout.trim()
+                ),
+            )
+        })
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(not(unix))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+/// The local address this host would send packets to `peer` from.
+///
+/// Connecting a UDP socket only looks up the route, nothing is sent.
+pub fn route_to(peer: IpAddr) -> io::Result<IpAddr> {
+    let unspecified: IpAddr = match peer {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(unspecified, 0))?;
+    socket.connect(SocketAddr::new(peer, 9))?;
+    Ok(socket.local_addr()?.ip())
+}
@@ -0,0 +1,215 @@
+//! SOCKS over QUIC, with each session on a bidirectional stream of a
+//! connection that lasts, so that bursty clients skip the TCP and TLS
+//! handshakes of every new session.
+
+use crate::client_stream::ClientStream;
+use quinn::{Connection, Endpoint, RecvStream, SendStream};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{Mutex, mpsc};
+use tokio_rustls::rustls::{self, Certificate, OwnedTrustAnchor, RootCertStore};
+
+/// How often an idle connection to an upstream is pinged, well within the
+/// idle timeout, so that it lasts between bursts.
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+/// Certificates trusted to sign upstreams besides the Mozilla roots.
+static EXTRA_ROOTS: OnceLock<Vec<Certificate>> = OnceLock::new();
+
+/// One bidirectional stream of a QUIC connection.
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+}
+
+impl QuicStream {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+/// Listen for QUIC clients on `addr`, terminating TLS with `tls`.
+pub fn listen(addr: &str, tls: Arc<rustls::ServerConfig>) -> io::Result<Endpoint> {
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to listen on"))?;
+    Endpoint::server(quinn::ServerConfig::with_crypto(tls), addr)
+}
+
+/// Accept the connections of clients on `endpoint`, queueing every stream
+/// they open with their address until `streams` is dropped.
+pub async fn accept(endpoint: Endpoint, streams: mpsc::Sender<(ClientStream, SocketAddr)>) {
+    let local_addr = match endpoint.local_addr() {
+        Ok(addr) => addr,
+        Err(err) => {
+            error!("QUIC endpoint has no address: {}", err);
+            return;
+        }
+    };
+    while let Some(connecting) = endpoint.accept().await {
+        let streams = streams.clone();
+        tokio::spawn(async move {
+            let connection = match connecting.await {
+                Ok(connection) => connection,
+                Err(err) => {
+                    debug!("QUIC handshake failed: {}", err);
+                    return;
+                }
+            };
+            let peer_addr = connection.remote_address();
+            let certificate = connection
+                .peer_identity()
+                .and_then(|identity| identity.downcast::<Vec<Certificate>>().ok())
+                .and_then(|certificates| certificates.into_iter().next());
+            loop {
+                let (send, recv) = match connection.accept_bi().await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        debug!("QUIC connection from {} closed: {}", peer_addr, err);
+                        return;
+                    }
+                };
+                let stream = QuicStream {
+                    send,
+                    recv,
+                    local_addr,
+                    peer_addr,
+                };
+                let client = ClientStream::quic(stream, certificate.clone());
+                if streams.send((client, peer_addr)).await.is_err() {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+/// Also trust the PEM certificates in `path` to sign QUIC upstreams.
+pub fn trust(path: &Path) -> io::Result<()> {
+    let certificates = crate::tls::read_certs(path)?;
+    EXTRA_ROOTS
+        .set(certificates)
+        .map_err(|_| io::Error::other("QUIC upstream roots already set"))
+}
+
+/// Opens streams to an upstream proxy, over one connection kept for as long
+/// as it works.
+#[derive(Default)]
+pub struct Dialer {
+    connection: Mutex<Option<Connection>>,
+}
+
+impl Dialer {
+    /// A new stream to the proxy at `addr`, as `host:port`.
+    pub async fn open(&self, addr: &str) -> io::Result<QuicStream> {
+        let connection = {
+            let mut cached = self.connection.lock().await;
+            match &*cached {
+                Some(connection) if connection.close_reason().is_none() => connection.clone(),
+                _ => {
+                    let connection = connect(addr).await?;
+                    *cached = Some(connection.clone());
+                    connection
+                }
+            }
+        };
+        let (send, recv) = connection.open_bi().await?;
+        Ok(QuicStream {
+            send,
+            recv,
+            local_addr: SocketAddr::new(
+                connection
+                    .local_ip()
+                    .unwrap_or(Ipv4Addr::UNSPECIFIED.into()),
+                0,
+            ),
+            peer_addr: connection.remote_address(),
+        })
+    }
+}
+
+async fn connect(addr: &str) -> io::Result<Connection> {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let remote = tokio::net::lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "upstream has no address"))?;
+    let local = match remote {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let mut endpoint = Endpoint::client(local)?;
+    endpoint.set_default_client_config(client_config()?);
+    let connection = endpoint
+        .connect(remote, host)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?
+        .await?;
+    Ok(connection)
+}
+
+fn client_config() -> io::Result<quinn::ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    for certificate in EXTRA_ROOTS.get().into_iter().flatten() {
+        roots
+            .add(certificate)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    }
+    let tls = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let mut transport = quinn::TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEP_ALIVE));
+    let mut config = quinn::ClientConfig::new(Arc::new(tls));
+    config.transport_config(Arc::new(transport));
+    Ok(config)
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,202 @@
+//! Per-user transfer quotas, counted from the accounting reports and checked
+//! whenever a client sends a new command.
+
+use crate::accounting::Accounting;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How often quota usage starts over, in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    Daily,
+    Monthly,
+}
+
+impl FromStr for Period {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "daily" => Ok(Period::Daily),
+            "monthly" => Ok(Period::Monthly),
+            _ => Err(format!("unknown quota period `{}`", s)),
+        }
+    }
+}
+
+impl Period {
+    /// A number for the day or month `time` falls in.
+    fn index(self, time: SystemTime) -> u64 {
+        let days = time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs() / 86_400);
+        match self {
+            Period::Daily => days,
+            Period::Monthly => {
+                let (year, month) = year_month(days);
+                year * 12 + month
+            }
+        }
+    }
+}
+
+/// The year and month (1 to 12) of a day counted from 1970-01-01, after
+/// Howard Hinnant's `civil_from_days`.
+fn year_month(days: u64) -> (u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    (era * 400 + year_of_era + u64::from(month <= 2), month)
+}
+
+/// Where quota usage is kept across restarts.
+pub trait QuotaStore: Send + Sync {
+    /// The period and usage saved last, if any.
+    fn load(&self) -> io::Result<Option<(u64, HashMap<String, u64>)>>;
+    fn save(&self, period: u64, usage: &HashMap<String, u64>) -> io::Result<()>;
+}
+
+/// Keeps quota usage in a JSON file, replaced as a whole on every save.
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(path: PathBuf) -> Self {
+        FileStore { path }
+    }
+}
+
+impl QuotaStore for FileStore {
+    fn load(&self) -> io::Result<Option<(u64, HashMap<String, u64>)>> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a quota file", self.path.display()),
+            )
+        };
+        let saved: Value = serde_json::from_str(&text).map_err(|_| invalid())?;
+        let period = saved["period"].as_u64().ok_or_else(invalid)?;
+        let usage = saved["usage"]
+            .as_object()
+            .ok_or_else(invalid)?
+            .iter()
+            .map(|(user, bytes)| Some((user.clone(), bytes.as_u64()?)))
+            .collect::<Option<_>>()
+            .ok_or_else(invalid)?;
+        Ok(Some((period, usage)))
+    }
+
+    fn save(&self, period: u64, usage: &HashMap<String, u64>) -> io::Result<()> {
+        // Write aside and rename, so that a crash never leaves half a file.
+        let partial = self.path.with_extension("partial");
+        fs::write(
+            &partial,
+            json!({ "period": period, "usage": usage }).to_string(),
+        )?;
+        fs::rename(&partial, &self.path)
+    }
+}
+
+/// The bytes, up and down together, each user may relay per period.
+pub struct Quotas {
+    limit: u64,
+    period: Period,
+    state: Mutex<State>,
+    store: Option<Box<dyn QuotaStore>>,
+}
+
+struct State {
+    period: u64,
+    usage: HashMap<String, u64>,
+    /// Whether `usage` changed since it was last saved.
+    dirty: bool,
+}
+
+impl Quotas {
+    /// Quotas of `limit` bytes per period, resuming the usage saved in `store`.
+    pub fn new(limit: u64, period: Period, store: Option<Box<dyn QuotaStore>>) -> io::Result<Self> {
+        let saved = match &store {
+            Some(store) => store.load()?,
+            None => None,
+        };
+        let (saved_period, usage) = saved.unwrap_or_default();
+        let quotas = Quotas {
+            limit,
+            period,
+            state: Mutex::new(State {
+                period: saved_period,
+                usage,
+                dirty: false,
+            }),
+            store,
+        };
+        quotas.roll(&mut quotas.state.lock().unwrap());
+        Ok(quotas)
+    }
+
+    /// Whether `user` used up its quota for this period.
+    pub fn exceeded(&self, user: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        self.roll(&mut state);
+        state
+            .usage
+            .get(user)
+            .is_some_and(|&used| used >= self.limit)
+    }
+
+    /// Save the usage, if it changed, to the store.
+    pub fn save(&self) -> io::Result<()> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.dirty {
+            store.save(state.period, &state.usage)?;
+            state.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Start over when a new period has begun.
+    fn roll(&self, state: &mut State) {
+        let current = self.period.index(SystemTime::now());
+        if state.period != current {
+            state.period = current;
+            state.usage.clear();
+            state.dirty = true;
+        }
+    }
+}
+
+impl Accounting for Quotas {
+    fn record(&self, user: Option<&str>, bytes_up: u64, bytes_down: u64) {
+        let Some(user) = user else {
+            return;
+        };
+        let mut state = self.state.lock().unwrap();
+        self.roll(&mut state);
+        *state.usage.entry(user.to_string()).or_default() += bytes_up + bytes_down;
+        state.dirty = true;
+    }
+}
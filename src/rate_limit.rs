@@ -0,0 +1,147 @@
+//! Rate limits: token buckets on the bytes relayed per session, per user and
+//! in total, and leaky buckets on how fast each source may open connections.
+//! Byte limits delay traffic rather than drop it, while connections over the
+//! accept rate are refused.
+
+use crate::client_stream::Source;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::time::{Instant, sleep};
+
+/// A token bucket refilled at `rate` bytes per second, holding at most one
+/// second worth of tokens.
+///
+/// Callers may take more tokens than are available; the bucket goes into
+/// debt and the caller sleeps until it would have been repaid, so large
+/// chunks are never starved by small ones.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        let rate = rate.max(1) as f64;
+        TokenBucket {
+            rate,
+            state: Mutex::new(BucketState {
+                tokens: rate,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub async fn acquire(&self, amount: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * self.rate).min(self.rate);
+            state.last_refill = now;
+            state.tokens -= amount as f64;
+            if state.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-state.tokens / self.rate)
+        };
+        sleep(wait).await;
+    }
+}
+
+/// Sources tracked before drained ones are purged.
+const MAX_TRACKED_SOURCES: usize = 4096;
+
+/// A leaky bucket per source address on the rate of new connections.
+///
+/// Each connection adds one to its source's bucket, which leaks `rate` per
+/// second; connections that would overflow `burst` are refused.
+#[derive(Debug)]
+pub struct AcceptLimit {
+    rate: f64,
+    burst: f64,
+    sources: Mutex<HashMap<Source, BucketState>>,
+}
+
+impl AcceptLimit {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        AcceptLimit {
+            rate,
+            burst: burst.max(1) as f64,
+            sources: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a new connection from `source`, or return false if it comes
+    /// too fast.
+    pub fn allow(&self, source: Source) -> bool {
+        let mut sources = self.sources.lock().unwrap();
+        let now = Instant::now();
+        if sources.len() >= MAX_TRACKED_SOURCES && !sources.contains_key(&source) {
+            let rate = self.rate;
+            sources.retain(|_, bucket| {
+                bucket.tokens > now.duration_since(bucket.last_refill).as_secs_f64() * rate
+            });
+        }
+
+        let bucket = sources.entry(source).or_insert(BucketState {
+            tokens: 0.0,
+            last_refill: now,
+        });
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens - elapsed * self.rate).max(0.0);
+        bucket.last_refill = now;
+        if bucket.tokens + 1.0 > self.burst {
+            return false;
+        }
+        bucket.tokens += 1.0;
+        true
+    }
+}
+
+/// Per-session, per-user and global byte rate limits applied to relayed
+/// traffic.
+///
+/// Both directions of a session draw from the same buckets.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    session: Option<TokenBucket>,
+    /// Shared by the sessions of the authenticated user, once known.
+    user: OnceLock<Arc<TokenBucket>>,
+    global: Option<Arc<TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(session_rate: Option<u64>, global: Option<Arc<TokenBucket>>) -> Self {
+        RateLimiter {
+            session: session_rate.map(TokenBucket::new),
+            user: OnceLock::new(),
+            global,
+        }
+    }
+
+    /// Also draw from `user`'s bucket, after authentication.
+    pub fn limit_user(&self, user: Arc<TokenBucket>) {
+        let _ = self.user.set(user);
+    }
+
+    /// Wait until `amount` bytes may be forwarded.
+    pub async fn throttle(&self, amount: usize) {
+        if let Some(session) = &self.session {
+            session.acquire(amount).await;
+        }
+        if let Some(user) = self.user.get() {
+            user.acquire(amount).await;
+        }
+        if let Some(global) = &self.global {
+            global.acquire(amount).await;
+        }
+    }
+}
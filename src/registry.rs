@@ -0,0 +1,279 @@
+//! The sessions in progress, so that they can be listed and ended one by one
+//! from outside, e.g. to cut off an abusive client. Sessions leaving the
+//! registry are handed to its audit sink, and their bytes to its accounting.
+
+use crate::accounting::Accounting;
+use crate::audit::{AuditRecord, AuditSink};
+use crate::auth::{Identity, Method};
+use crate::relay::Traffic;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::time::{MissedTickBehavior, interval};
+use tokio_util::sync::CancellationToken;
+
+/// All registered sessions, by id.
+pub struct Registry {
+    next_id: AtomicU64,
+    sessions: Mutex<BTreeMap<u64, Arc<Entry>>>,
+    audit: Option<Box<dyn AuditSink>>,
+    accounting: Vec<&'static dyn Accounting>,
+}
+
+struct Entry {
+    started: SystemTime,
+    details: Mutex<Details>,
+    traffic: Traffic,
+    /// The traffic already reported to the accounting.
+    accounted: Mutex<(u64, u64)>,
+    kill: CancellationToken,
+}
+
+impl Entry {
+    /// The bytes relayed up and down since the last call.
+    fn unaccounted(&self) -> (u64, u64) {
+        let mut accounted = self.accounted.lock().unwrap();
+        let up = self.traffic.up.load(Ordering::Relaxed);
+        let down = self.traffic.down.load(Ordering::Relaxed);
+        let unaccounted = (up - accounted.0, down - accounted.1);
+        *accounted = (up, down);
+        unaccounted
+    }
+}
+
+#[derive(Clone)]
+struct Details {
+    peer: SocketAddr,
+    user: Option<String>,
+    auth_method: Option<Method>,
+    command: Option<String>,
+    target: Option<String>,
+    reply: Option<u16>,
+    close_reason: Option<String>,
+    country: Option<String>,
+    asn: Option<u32>,
+}
+
+/// What a session is doing, as of when it was listed.
+#[cfg(feature = "admin")]
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub user: Option<String>,
+    pub auth_method: Option<Method>,
+    pub command: Option<String>,
+    pub target: Option<String>,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub started: SystemTime,
+}
+
+impl Registry {
+    pub fn new(
+        audit: Option<Box<dyn AuditSink>>,
+        accounting: Vec<&'static dyn Accounting>,
+    ) -> Self {
+        Registry {
+            next_id: AtomicU64::new(0),
+            sessions: Mutex::new(BTreeMap::new()),
+            audit,
+            accounting,
+        }
+    }
+
+    /// Register a session from `peer`, which can be killed until the
+    /// returned registration is dropped. Its shutdown token is a child of
+    /// `shutdown`.
+    pub fn register(&'static self, peer: SocketAddr, shutdown: &CancellationToken) -> Registration {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let entry = Arc::new(Entry {
+            started: SystemTime::now(),
+            details: Mutex::new(Details {
+                peer,
+                user: None,
+                auth_method: None,
+                command: None,
+                target: None,
+                reply: None,
+                close_reason: None,
+                country: None,
+                asn: None,
+            }),
+            traffic: Traffic::default(),
+            accounted: Mutex::new((0, 0)),
+            kill: shutdown.child_token(),
+        });
+        self.sessions.lock().unwrap().insert(id, entry.clone());
+        Registration {
+            registry: self,
+            id,
+            entry,
+        }
+    }
+
+    /// The sessions in progress, oldest first.
+    #[cfg(feature = "admin")]
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .iter()
+            .map(|(&id, entry)| {
+                let details = entry.details.lock().unwrap().clone();
+                SessionInfo {
+                    id,
+                    peer: details.peer,
+                    user: details.user,
+                    auth_method: details.auth_method,
+                    command: details.command,
+                    target: details.target,
+                    bytes_up: entry.traffic.up.load(Ordering::Relaxed),
+                    bytes_down: entry.traffic.down.load(Ordering::Relaxed),
+                    started: entry.started,
+                }
+            })
+            .collect()
+    }
+
+    /// End the session `id`, if it is still in progress.
+    #[cfg(feature = "admin")]
+    pub fn kill(&self, id: u64) -> bool {
+        match self.sessions.lock().unwrap().get(&id) {
+            Some(entry) => {
+                entry.kill.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Report the traffic of running sessions to every accounting each
+    /// `period`, summed up by user.
+    pub async fn account_every(&self, period: Duration) {
+        if self.accounting.is_empty() {
+            return;
+        }
+        let mut ticks = interval(period);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            let mut usage: HashMap<Option<String>, (u64, u64)> = HashMap::new();
+            for entry in self.sessions.lock().unwrap().values() {
+                let (up, down) = entry.unaccounted();
+                if up > 0 || down > 0 {
+                    let user = entry.details.lock().unwrap().user.clone();
+                    let total = usage.entry(user).or_default();
+                    total.0 += up;
+                    total.1 += down;
+                }
+            }
+            for (user, (up, down)) in usage {
+                for accounting in &self.accounting {
+                    accounting.record(user.as_deref(), up, down);
+                }
+            }
+        }
+    }
+}
+
+/// A session's place in the registry, given up when dropped.
+pub struct Registration {
+    registry: &'static Registry,
+    id: u64,
+    entry: Arc<Entry>,
+}
+
+impl Registration {
+    /// Cancelled when the session is killed or the server shuts down.
+    pub fn shutdown(&self) -> &CancellationToken {
+        &self.entry.kill
+    }
+
+    pub fn traffic(&self) -> &Traffic {
+        &self.entry.traffic
+    }
+
+    pub fn set_peer(&self, peer: SocketAddr) {
+        self.entry.details.lock().unwrap().peer = peer;
+    }
+
+    pub fn user(&self) -> Option<String> {
+        self.entry.details.lock().unwrap().user.clone()
+    }
+
+    /// Who the client authenticated as; sessions that never did count as
+    /// asked for no credentials.
+    pub fn identity(&self) -> Identity {
+        let details = self.entry.details.lock().unwrap();
+        Identity {
+            user: details.user.clone(),
+            method: details.auth_method.unwrap_or(Method::None),
+        }
+    }
+
+    pub fn set_identity(&self, identity: Identity) {
+        let mut details = self.entry.details.lock().unwrap();
+        details.user = identity.user;
+        details.auth_method = Some(identity.method);
+    }
+
+    pub fn set_request(&self, command: &str, target: &str) {
+        let mut details = self.entry.details.lock().unwrap();
+        details.command = Some(command.to_string());
+        details.target = Some(target.to_string());
+    }
+
+    /// Note where the target is, for the audit record.
+    #[cfg(feature = "geoip")]
+    pub fn set_location(&self, country: Option<String>, asn: Option<u32>) {
+        let mut details = self.entry.details.lock().unwrap();
+        details.country = country;
+        details.asn = asn;
+    }
+
+    /// Note the reply sent to the client, for the audit record.
+    pub fn set_reply(&self, reply: u16) {
+        self.entry.details.lock().unwrap().reply = Some(reply);
+    }
+
+    /// Note how the session ended, for the audit record.
+    pub fn set_closed(&self, reason: String) {
+        self.entry.details.lock().unwrap().close_reason = Some(reason);
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.sessions.lock().unwrap().remove(&self.id);
+        let details = self.entry.details.lock().unwrap().clone();
+        let (up, down) = self.entry.unaccounted();
+        if up > 0 || down > 0 {
+            for accounting in &self.registry.accounting {
+                accounting.record(details.user.as_deref(), up, down);
+            }
+        }
+        let Some(audit) = &self.registry.audit else {
+            return;
+        };
+        audit.record(&AuditRecord {
+            started: self.entry.started,
+            ended: SystemTime::now(),
+            user: details.user,
+            auth_method: details.auth_method.map(Method::as_str),
+            client: details.peer,
+            command: details.command,
+            target: details.target,
+            bytes_up: self.entry.traffic.up.load(Ordering::Relaxed),
+            bytes_down: self.entry.traffic.down.load(Ordering::Relaxed),
+            close_reason: details
+                .close_reason
+                .unwrap_or_else(|| "unknown".to_string()),
+            reply: details.reply,
+            country: details.country,
+            asn: details.asn,
+        });
+    }
+}
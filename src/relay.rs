@@ -0,0 +1,487 @@
+//! Connecting to the target of a CONNECT request, directly or through an
+//! upstream, and relaying the session's bytes both ways until it ends, with
+//! the buffers, timeouts and rate limits it was given. Plain TCP sessions
+//! are spliced in the kernel on Linux, when built with the `splice` feature.
+
+use crate::buffer_pool::BufferPool;
+use crate::client_stream::ClientStream;
+use crate::dns::Resolver;
+use crate::egress::Egress;
+use crate::guard::DestinationGuard;
+use crate::happy_eyeballs::{self, FamilyPreference};
+use crate::monitoring;
+use crate::outbound::Outbound;
+use crate::proxy_protocol;
+use crate::rate_limit::RateLimiter;
+use crate::socket_opts::SocketOpts;
+use crate::upstream::{Lease, OutboundConnector};
+use fast_socks5::server::{Socks5ServerProtocol, states};
+use fast_socks5::util::target_addr::TargetAddr;
+use fast_socks5::{ReplyError, Result, SocksError};
+use ipnet::IpNet;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{Instant, sleep_until, timeout};
+use tokio_util::sync::CancellationToken;
+
+#[cfg(all(feature = "splice", target_os = "linux"))]
+mod splice;
+
+/// Decides which resolved addresses a client may be connected to.
+pub trait DestinationPolicy: Sync {
+    /// Whether `addr` may be connected to, as an address of `domain` when
+    /// the client asked for one.
+    fn permits(&self, domain: Option<&str>, addr: SocketAddr) -> bool;
+}
+
+/// How outbound connections to client targets are made.
+pub struct ConnectOptions<'a> {
+    /// Maximum time to connect, including the name lookup.
+    pub timeout: Duration,
+    /// Proxies to dial targets through instead of connecting directly.
+    pub upstream: Option<&'a dyn OutboundConnector>,
+    pub resolver: &'a Resolver,
+    /// Checked against the resolved addresses of direct connections.
+    pub guard: DestinationGuard,
+    /// Also checked against every resolved address, so that a name can't
+    /// lead to a destination the client couldn't ask for by address.
+    pub policy: &'a dyn DestinationPolicy,
+    /// Family of the first address tried when a target has both.
+    pub family_preference: FamilyPreference,
+    /// Time to wait on a connection attempt before also trying the next
+    /// address.
+    pub attempt_delay: Duration,
+    /// Local address and interface of direct connections.
+    pub egress: Egress<'a>,
+    pub socket_opts: SocketOpts,
+    /// Direct connections to targets in these networks start with a PROXY
+    /// protocol v2 header carrying `client_addr`.
+    pub proxy_protocol_to: &'a [IpNet],
+    pub client_addr: SocketAddr,
+}
+
+/// Connect to the target of a CONNECT request, reply to the client and relay
+/// the two streams until both directions are closed.
+///
+/// The reply carries the local address of the outbound connection, with its
+/// IP replaced by `reply_ip` when set.
+pub async fn run_tcp_proxy(
+    proto: Socks5ServerProtocol<ClientStream, states::CommandRead>,
+    target_addr: &TargetAddr,
+    options: &ConnectOptions<'_>,
+    reply_ip: Option<IpAddr>,
+    relay_options: RelayOptions<'_>,
+    limiter: &RateLimiter,
+    shutdown: &CancellationToken,
+) -> Result<ProxyStats> {
+    let (outbound, _lease) = match connect(target_addr, options).await {
+        Ok(connected) => connected,
+        Err(err) => {
+            proto.reply_error(&reply_error_for(&err)).await?;
+            return Err(err);
+        }
+    };
+    run_tcp_proxy_with(proto, outbound, reply_ip, relay_options, limiter, shutdown).await
+}
+
+/// Like [`run_tcp_proxy`], with the target already connected to as
+/// `outbound`, e.g. through a dialer of the caller's own.
+pub async fn run_tcp_proxy_with(
+    proto: Socks5ServerProtocol<ClientStream, states::CommandRead>,
+    outbound: Outbound,
+    reply_ip: Option<IpAddr>,
+    relay_options: RelayOptions<'_>,
+    limiter: &RateLimiter,
+    shutdown: &CancellationToken,
+) -> Result<ProxyStats> {
+    let mut bound_addr = outbound.local_addr()?;
+    if let Some(ip) = reply_ip {
+        bound_addr.set_ip(ip);
+    }
+    let inner = proto.reply_success(bound_addr).await?;
+
+    Ok(relay_tcp(inner, outbound, relay_options, limiter, shutdown).await)
+}
+
+/// Connect to `target_addr`, directly or through the upstream of `options`,
+/// whose lease is to be held until the connection is done with.
+pub async fn connect(
+    target_addr: &TargetAddr,
+    options: &ConnectOptions<'_>,
+) -> Result<(Outbound, Lease)> {
+    let connect = async {
+        if let Some(upstream) = options.upstream {
+            return upstream.connect(target_addr).await;
+        }
+        let addrs: Vec<_> = options
+            .resolver
+            .resolve_all(target_addr)
+            .await?
+            .into_iter()
+            .filter(|addr| options.guard.allows(*addr))
+            .collect();
+        if addrs.is_empty() {
+            debug!("Refusing internal destination {}", target_addr);
+            return Err(ReplyError::ConnectionNotAllowed.into());
+        }
+        let domain = match target_addr {
+            TargetAddr::Domain(domain, _) => Some(domain.as_str()),
+            TargetAddr::Ip(_) => None,
+        };
+        let addrs: Vec<_> = addrs
+            .into_iter()
+            .filter(|addr| options.policy.permits(domain, *addr))
+            .collect();
+        if addrs.is_empty() {
+            debug!("Refusing destination {}, denied by ACL", target_addr);
+            return Err(ReplyError::ConnectionNotAllowed.into());
+        }
+        let addrs: Vec<_> = addrs
+            .into_iter()
+            .filter(|addr| options.egress.reaches(*addr))
+            .collect();
+        if addrs.is_empty() {
+            debug!(
+                "No address of {} is reachable from the egress address",
+                target_addr
+            );
+            return Err(ReplyError::NetworkUnreachable.into());
+        }
+        let addrs = happy_eyeballs::interleave(addrs, options.family_preference);
+        Ok::<_, SocksError>((
+            Outbound::Tcp(
+                happy_eyeballs::connect(&addrs, options.attempt_delay, &options.egress).await?,
+            ),
+            Lease::default(),
+        ))
+    };
+
+    let started = Instant::now();
+    let (mut outbound, lease) = timeout(options.timeout, connect)
+        .await
+        .map_err(|_| ReplyError::ConnectionTimeout)??;
+    monitoring::connect_latency(started.elapsed());
+    if let Outbound::Tcp(socket) = &outbound {
+        options.socket_opts.apply(socket)?;
+    }
+
+    let peer = outbound.peer_addr()?;
+    if options.upstream.is_none()
+        && options
+            .proxy_protocol_to
+            .iter()
+            .any(|net| net.contains(&peer.ip().to_canonical()))
+    {
+        let header = proxy_protocol::encode_v2(options.client_addr, peer);
+        outbound.write_all(&header).await?;
+    }
+    Ok((outbound, lease))
+}
+
+/// The reply sent to the client when connecting to its target fails.
+pub fn reply_error_for(err: &SocksError) -> ReplyError {
+    match err {
+        SocksError::ReplyError(reply) => *reply,
+        SocksError::Io(err) => match err.kind() {
+            io::ErrorKind::NetworkUnreachable => ReplyError::NetworkUnreachable,
+            io::ErrorKind::HostUnreachable => ReplyError::HostUnreachable,
+            io::ErrorKind::ConnectionRefused => ReplyError::ConnectionRefused,
+            io::ErrorKind::TimedOut => ReplyError::TtlExpired,
+            io::ErrorKind::PermissionDenied => ReplyError::ConnectionNotAllowed,
+            _ => ReplyError::GeneralFailure,
+        },
+        _ => ReplyError::GeneralFailure,
+    }
+}
+
+/// Why a relayed session ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TerminationReason {
+    /// Both sides closed their write half.
+    Closed,
+    /// Reading from or writing to the client failed.
+    ClientError,
+    /// Reading from or writing to the target failed.
+    TargetError,
+    /// No data flowed in either direction for the idle timeout.
+    Idle,
+    /// The server is shutting down.
+    Shutdown,
+}
+
+/// Transfer statistics of a relayed session.
+#[derive(Debug, Clone, Copy)]
+pub struct ProxyStats {
+    /// Bytes sent from the client to the target.
+    pub bytes_up: u64,
+    /// Bytes sent from the target to the client.
+    pub bytes_down: u64,
+    pub duration: Duration,
+    pub termination: TerminationReason,
+}
+
+impl fmt::Display for ProxyStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes up, {} bytes down in {:.1?} ({:?})",
+            self.bytes_up, self.bytes_down, self.duration, self.termination
+        )
+    }
+}
+
+/// Bytes relayed by a session so far, readable while it runs.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    /// Bytes sent from the client to the target.
+    pub up: AtomicU64,
+    /// Bytes sent from the target to the client.
+    pub down: AtomicU64,
+}
+
+impl Traffic {
+    fn load(&self) -> (u64, u64) {
+        (
+            self.up.load(Ordering::Relaxed),
+            self.down.load(Ordering::Relaxed),
+        )
+    }
+
+    /// The stats of a relay that started with the counts at `before`.
+    fn stats_since(
+        &self,
+        before: (u64, u64),
+        started: Instant,
+        termination: TerminationReason,
+    ) -> ProxyStats {
+        let (up, down) = self.load();
+        let (bytes_up, bytes_down) = (up - before.0, down - before.1);
+        monitoring::bytes_relayed(bytes_up, bytes_down);
+        ProxyStats {
+            bytes_up,
+            bytes_down,
+            duration: started.elapsed(),
+            termination,
+        }
+    }
+}
+
+/// A copy failure, tagged with the side that caused it.
+enum CopyError {
+    Read(io::Error),
+    Write(io::Error),
+}
+
+/// How relayed sessions move data and when they end.
+#[derive(Clone, Copy)]
+pub struct RelayOptions<'a> {
+    /// Where the copy buffers come from; their size is also the most that
+    /// is moved at once.
+    pub buffers: &'a BufferPool,
+    /// End the session once no data has flowed for this long.
+    pub idle_timeout: Option<Duration>,
+    /// Keep relaying the other direction after one side sends EOF, for
+    /// protocols that half-close their connection. Otherwise the first EOF
+    /// ends the session.
+    pub half_close: bool,
+    /// Where to count the relayed bytes as they go, for whoever watches the
+    /// session.
+    pub traffic: Option<&'a Traffic>,
+}
+
+/// Relay two streams in both directions until each side has sent EOF,
+/// either side fails, nothing is sent for the idle timeout or `shutdown` is
+/// cancelled, applying `limiter` to every chunk.
+pub async fn relay<C, T>(
+    client: C,
+    target: T,
+    options: RelayOptions<'_>,
+    limiter: &RateLimiter,
+    shutdown: &CancellationToken,
+) -> ProxyStats
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut target_read, mut target_write) = tokio::io::split(target);
+    let own_traffic = Traffic::default();
+    let traffic = options.traffic.unwrap_or(&own_traffic);
+    let before = traffic.load();
+    let started = Instant::now();
+    let activity = Activity::new(started);
+
+    let up = async {
+        copy(
+            &mut client_read,
+            &mut target_write,
+            options.buffers,
+            limiter,
+            &activity,
+            &traffic.up,
+        )
+        .await
+        .map_err(CopyError::blame_up)
+    };
+    let down = async {
+        copy(
+            &mut target_read,
+            &mut client_write,
+            options.buffers,
+            limiter,
+            &activity,
+            &traffic.down,
+        )
+        .await
+        .map_err(CopyError::blame_down)
+    };
+    let termination = drive(up, down, &activity, options, shutdown).await;
+    traffic.stats_since(before, started, termination)
+}
+
+/// Relay a client and a target like [`relay`], moving the data with
+/// `splice(2)` when built with the `splice` feature on Linux, both are on
+/// plain TCP and the kernel allows it.
+pub async fn relay_tcp(
+    client: ClientStream,
+    target: Outbound,
+    options: RelayOptions<'_>,
+    limiter: &RateLimiter,
+    shutdown: &CancellationToken,
+) -> ProxyStats {
+    #[cfg(all(feature = "splice", target_os = "linux"))]
+    if let (Some(socket), Outbound::Tcp(target)) = (client.plain_tcp(), &target) {
+        match (splice::Pipe::new(), splice::Pipe::new()) {
+            (Ok(up), Ok(down)) => {
+                return splice::relay(socket, target, [up, down], options, limiter, shutdown).await;
+            }
+            (Err(err), _) | (_, Err(err)) => {
+                debug!("Falling back to copying, no pipe for splice: {}", err);
+            }
+        }
+    }
+    relay(client, target, options, limiter, shutdown).await
+}
+
+type CopyResult = Result<(), (TerminationReason, io::Error)>;
+
+/// Run the two directions of a relay until they end as `options` asks, the
+/// relay is idle for too long or `shutdown` is cancelled.
+async fn drive(
+    up: impl Future<Output = CopyResult>,
+    down: impl Future<Output = CopyResult>,
+    activity: &Activity,
+    options: RelayOptions<'_>,
+    shutdown: &CancellationToken,
+) -> TerminationReason {
+    let transfer = async {
+        if options.half_close {
+            tokio::try_join!(up, down).map(|_| ())
+        } else {
+            tokio::select! {
+                result = up => result,
+                result = down => result,
+            }
+        }
+    };
+    let idle = async {
+        match options.idle_timeout {
+            Some(limit) => activity.idle_for(limit).await,
+            None => std::future::pending().await,
+        }
+    };
+    let result = tokio::select! {
+        result = transfer => result.map(|()| TerminationReason::Closed),
+        _ = idle => Ok(TerminationReason::Idle),
+        _ = shutdown.cancelled() => Ok(TerminationReason::Shutdown),
+    };
+    match result {
+        Ok(reason) => reason,
+        Err((reason, err)) => {
+            debug!("Relay ended with {:?}: {}", reason, err);
+            reason
+        }
+    }
+}
+
+impl CopyError {
+    /// Blame the client for reads and the target for writes.
+    fn blame_up(self) -> (TerminationReason, io::Error) {
+        match self {
+            CopyError::Read(err) => (TerminationReason::ClientError, err),
+            CopyError::Write(err) => (TerminationReason::TargetError, err),
+        }
+    }
+
+    /// Blame the target for reads and the client for writes.
+    fn blame_down(self) -> (TerminationReason, io::Error) {
+        match self {
+            CopyError::Read(err) => (TerminationReason::TargetError, err),
+            CopyError::Write(err) => (TerminationReason::ClientError, err),
+        }
+    }
+}
+
+/// When data last flowed through a relay, in either direction.
+struct Activity {
+    started: Instant,
+    /// Milliseconds from `started` to the last chunk relayed.
+    last: AtomicU64,
+}
+
+impl Activity {
+    fn new(started: Instant) -> Self {
+        Activity {
+            started,
+            last: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// Complete once nothing has been relayed for `limit`.
+    async fn idle_for(&self, limit: Duration) {
+        loop {
+            let last = self.started + Duration::from_millis(self.last.load(Ordering::Relaxed));
+            if last.elapsed() >= limit {
+                return;
+            }
+            sleep_until(last + limit).await;
+        }
+    }
+}
+
+async fn copy<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    buffers: &BufferPool,
+    limiter: &RateLimiter,
+    activity: &Activity,
+    total: &AtomicU64,
+) -> Result<(), CopyError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = buffers.get();
+    loop {
+        let n = reader.read(&mut buf).await.map_err(CopyError::Read)?;
+        if n == 0 {
+            return writer.shutdown().await.map_err(CopyError::Write);
+        }
+        limiter.throttle(n).await;
+        writer
+            .write_all(&buf[..n])
+            .await
+            .map_err(CopyError::Write)?;
+        activity.touch();
+        total.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
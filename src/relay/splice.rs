@@ -0,0 +1,125 @@
+//! Relaying between two TCP sockets with `splice(2)`, which moves the data
+//! through a pipe inside the kernel instead of a userspace buffer.
+
+use super::{Activity, CopyError, ProxyStats, RelayOptions, Traffic, drive};
+use crate::rate_limit::RateLimiter;
+use nix::fcntl::{OFlag, SpliceFFlags};
+use nix::sys::socket::Shutdown;
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// The kernel pipe one direction of the relay goes through.
+pub struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    pub fn new() -> io::Result<Self> {
+        let (read, write) = nix::unistd::pipe2(OFlag::O_NONBLOCK | OFlag::O_CLOEXEC)?;
+        Ok(Pipe { read, write })
+    }
+}
+
+/// Relay `client` and `target` like [`super::relay`], through `pipes`: the
+/// first for data sent by the client, the second for data sent back.
+pub async fn relay(
+    client: &TcpStream,
+    target: &TcpStream,
+    pipes: [Pipe; 2],
+    options: RelayOptions<'_>,
+    limiter: &RateLimiter,
+    shutdown: &CancellationToken,
+) -> ProxyStats {
+    let [up_pipe, down_pipe] = pipes;
+    let own_traffic = Traffic::default();
+    let traffic = options.traffic.unwrap_or(&own_traffic);
+    let before = traffic.load();
+    let started = Instant::now();
+    let activity = Activity::new(started);
+
+    let up = async {
+        copy(
+            client,
+            target,
+            &up_pipe,
+            options.buffers.buffer_size(),
+            limiter,
+            &activity,
+            &traffic.up,
+        )
+        .await
+        .map_err(CopyError::blame_up)
+    };
+    let down = async {
+        copy(
+            target,
+            client,
+            &down_pipe,
+            options.buffers.buffer_size(),
+            limiter,
+            &activity,
+            &traffic.down,
+        )
+        .await
+        .map_err(CopyError::blame_down)
+    };
+    let termination = drive(up, down, &activity, options, shutdown).await;
+    traffic.stats_since(before, started, termination)
+}
+
+async fn copy(
+    reader: &TcpStream,
+    writer: &TcpStream,
+    pipe: &Pipe,
+    chunk_size: usize,
+    limiter: &RateLimiter,
+    activity: &Activity,
+    total: &AtomicU64,
+) -> Result<(), CopyError> {
+    let flags = SpliceFFlags::SPLICE_F_MOVE | SpliceFFlags::SPLICE_F_NONBLOCK;
+    loop {
+        let n = reader
+            .async_io(Interest::READABLE, || {
+                Ok(nix::fcntl::splice(
+                    reader,
+                    None,
+                    &pipe.write,
+                    None,
+                    chunk_size,
+                    flags,
+                )?)
+            })
+            .await
+            .map_err(CopyError::Read)?;
+        if n == 0 {
+            return nix::sys::socket::shutdown(writer.as_raw_fd(), Shutdown::Write)
+                .map_err(|err| CopyError::Write(err.into()));
+        }
+        limiter.throttle(n).await;
+
+        // Drain the pipe before reading more, so it never fills up.
+        let mut pending = n;
+        while pending > 0 {
+            let written = writer
+                .async_io(Interest::WRITABLE, || {
+                    Ok(nix::fcntl::splice(
+                        &pipe.read, None, writer, None, pending, flags,
+                    )?)
+                })
+                .await
+                .map_err(CopyError::Write)?;
+            if written == 0 {
+                return Err(CopyError::Write(io::ErrorKind::WriteZero.into()));
+            }
+            pending -= written;
+        }
+        activity.touch();
+        total.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
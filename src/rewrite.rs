@@ -0,0 +1,126 @@
+//! Rewriting of client targets before they are resolved and connected to,
+//! e.g. to pin internal names to addresses or send blocked hosts to a
+//! warning page.
+
+use crate::acl::{Destination, parse_port_range};
+use fast_socks5::util::target_addr::TargetAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// Replaces the targets of client requests.
+pub trait TargetRewriter {
+    /// The target to connect to instead of `target`, if any.
+    fn rewrite(&self, target: &TargetAddr) -> Option<TargetAddr>;
+}
+
+/// Where a rewritten target goes; what is left out is kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Replacement {
+    Host(String),
+    Ip(IpAddr),
+    Port(u16),
+    HostPort(String, u16),
+    Addr(SocketAddr),
+}
+
+impl Replacement {
+    fn apply(&self, target: &TargetAddr) -> TargetAddr {
+        let port = match target {
+            TargetAddr::Ip(addr) => addr.port(),
+            TargetAddr::Domain(_, port) => *port,
+        };
+        match self {
+            Replacement::Host(host) => TargetAddr::Domain(host.clone(), port),
+            Replacement::Ip(ip) => TargetAddr::Ip(SocketAddr::new(*ip, port)),
+            Replacement::Port(port) => match target {
+                TargetAddr::Ip(addr) => TargetAddr::Ip(SocketAddr::new(addr.ip(), *port)),
+                TargetAddr::Domain(host, _) => TargetAddr::Domain(host.clone(), *port),
+            },
+            Replacement::HostPort(host, port) => TargetAddr::Domain(host.clone(), *port),
+            Replacement::Addr(addr) => TargetAddr::Ip(*addr),
+        }
+    }
+}
+
+impl FromStr for Replacement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid rewrite replacement `{}`", s);
+        if let Some(port) = s.strip_prefix(':') {
+            return port.parse().map(Replacement::Port).map_err(|_| invalid());
+        }
+        if let Ok(addr) = s.parse() {
+            return Ok(Replacement::Addr(addr));
+        }
+        if let Ok(ip) = s.trim_start_matches('[').trim_end_matches(']').parse() {
+            return Ok(Replacement::Ip(ip));
+        }
+        match s.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() => {
+                let port = port.parse().map_err(|_| invalid())?;
+                Ok(Replacement::HostPort(host.to_string(), port))
+            }
+            Some(_) => Err(invalid()),
+            None if !s.is_empty() => Ok(Replacement::Host(s.to_string())),
+            None => Err(invalid()),
+        }
+    }
+}
+
+/// A rewrite rule: `<destination> [port[-port]] <replacement>`.
+///
+/// The destination takes the forms of ACL rules. The replacement is
+/// `host:port`, `host` to keep the port, or `:port` to keep the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rewrite {
+    destination: Destination,
+    ports: Option<(u16, u16)>,
+    replacement: Replacement,
+}
+
+impl FromStr for Rewrite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<_> = s.split_whitespace().collect();
+        let (destination, ports, replacement) = match fields[..] {
+            [destination, replacement] => (destination, None, replacement),
+            [destination, ports, replacement] => {
+                (destination, Some(parse_port_range(ports)?), replacement)
+            }
+            _ => {
+                return Err(format!(
+                    "rewrite rule `{}` should be `<destination> [ports] <replacement>`",
+                    s
+                ));
+            }
+        };
+        Ok(Rewrite {
+            destination: destination.parse()?,
+            ports,
+            replacement: replacement.parse()?,
+        })
+    }
+}
+
+/// The first matching rule rewrites the target.
+impl TargetRewriter for [Rewrite] {
+    fn rewrite(&self, target: &TargetAddr) -> Option<TargetAddr> {
+        let (domain, ip, port) = match target {
+            TargetAddr::Ip(addr) => (None, Some(addr.ip()), addr.port()),
+            TargetAddr::Domain(name, port) => (
+                Some(name.trim_end_matches('.').to_ascii_lowercase()),
+                None,
+                *port,
+            ),
+        };
+        self.iter()
+            .find(|rule| {
+                rule.ports
+                    .is_none_or(|(first, last)| (first..=last).contains(&port))
+                    && rule.destination.matches(domain.as_deref(), ip)
+            })
+            .map(|rule| rule.replacement.apply(target))
+    }
+}
@@ -0,0 +1,62 @@
+//! TCP and IP options for the sockets on both sides of a session.
+
+use socket2::{SockRef, TcpKeepalive};
+use std::io;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// Socket options applied to accepted client sockets and to connections to
+/// CONNECT targets.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SocketOpts {
+    /// Disable Nagle's algorithm (`TCP_NODELAY`).
+    pub nodelay: bool,
+    pub keepalive: Option<Keepalive>,
+    /// `IP_TTL`, or the hop limit on IPv6 sockets.
+    pub ttl: Option<u32>,
+    /// Differentiated services code point to mark packets with, 0 to 63.
+    pub dscp: Option<u8>,
+}
+
+/// TCP keepalive probing (`SO_KEEPALIVE`).
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+    /// Idle time before the first probe.
+    pub time: Duration,
+    /// Time between probes, or the system default.
+    pub interval: Option<Duration>,
+}
+
+impl SocketOpts {
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        let socket = SockRef::from(stream);
+        let ipv6 = stream.local_addr()?.is_ipv6();
+        if self.nodelay {
+            socket.set_nodelay(true)?;
+        }
+        if let Some(keepalive) = self.keepalive {
+            let mut params = TcpKeepalive::new().with_time(keepalive.time);
+            if let Some(interval) = keepalive.interval {
+                params = params.with_interval(interval);
+            }
+            socket.set_tcp_keepalive(&params)?;
+        }
+        if let Some(ttl) = self.ttl {
+            if ipv6 {
+                socket.set_unicast_hops_v6(ttl)?;
+            } else {
+                socket.set_ttl(ttl)?;
+            }
+        }
+        if let Some(dscp) = self.dscp {
+            // The DSCP is the upper six bits of the TOS or traffic class byte.
+            let tos = u32::from(dscp) << 2;
+            if ipv6 {
+                socket.set_tclass_v6(tos)?;
+            } else {
+                socket.set_tos(tos)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+//! SOCKS4 and SOCKS4a, for legacy clients on the same port as SOCKS5: the
+//! request is read here and served like a SOCKS5 CONNECT, and the one-shot
+//! reply only says whether the connection was granted.
+
+use fast_socks5::util::target_addr::TargetAddr;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const SOCKS4_VERSION: u8 = 0x04;
+pub const SOCKS4_CMD_CONNECT: u8 = 0x01;
+
+const SOCKS4_REPLY_VERSION: u8 = 0x00;
+const SOCKS4_REPLY_GRANTED: u8 = 0x5a;
+const SOCKS4_REPLY_REJECTED: u8 = 0x5b;
+
+/// Upper bound for the NUL-terminated USERID and (SOCKS4a) hostname fields.
+const MAX_FIELD_LEN: usize = 255;
+
+/// A SOCKS4 or SOCKS4a request, as sent by the client right after connecting.
+#[derive(Debug)]
+pub struct Request {
+    pub command: u8,
+    pub target: TargetAddr,
+    pub user_id: String,
+}
+
+/// Read a SOCKS4 request. A destination IP of `0.0.0.x` (with `x != 0`) marks
+/// the SOCKS4a extension, in which case the hostname follows the USERID.
+pub async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Request> {
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header).await?;
+
+    let [version, command, port_hi, port_lo, a, b, c, d] = header;
+    if version != SOCKS4_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported SOCKS version {:#04x}", version),
+        ));
+    }
+    let port = u16::from_be_bytes([port_hi, port_lo]);
+    let ip = Ipv4Addr::new(a, b, c, d);
+
+    let user_id = read_nul_terminated(stream).await?;
+    let target = if a == 0 && b == 0 && c == 0 && d != 0 {
+        TargetAddr::Domain(read_nul_terminated(stream).await?, port)
+    } else {
+        TargetAddr::Ip(SocketAddr::new(ip.into(), port))
+    };
+
+    Ok(Request {
+        command,
+        target,
+        user_id,
+    })
+}
+
+/// Write the reply to a CONNECT request. DSTPORT and DSTIP are ignored by
+/// clients for CONNECT, so they are always zeroed.
+pub async fn write_reply<S: AsyncWrite + Unpin>(stream: &mut S, granted: bool) -> io::Result<()> {
+    stream
+        .write_all(&[SOCKS4_REPLY_VERSION, reply_code(granted), 0, 0, 0, 0, 0, 0])
+        .await
+}
+
+/// The reply code [`write_reply`] sends.
+pub fn reply_code(granted: bool) -> u8 {
+    if granted {
+        SOCKS4_REPLY_GRANTED
+    } else {
+        SOCKS4_REPLY_REJECTED
+    }
+}
+
+async fn read_nul_terminated<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<String> {
+    let mut field = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            0 => break,
+            byte if field.len() < MAX_FIELD_LEN => field.push(byte),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "SOCKS4 field exceeds 255 bytes",
+                ));
+            }
+        }
+    }
+    String::from_utf8(field)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "SOCKS4 field is not UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut bytes: &[u8]) -> io::Result<Request> {
+        read_request(&mut bytes).await
+    }
+
+    #[tokio::test]
+    async fn reads_socks4_request() {
+        let request = read(b"\x04\x01\x00\x50\x7f\x00\x00\x01alice\x00")
+            .await
+            .unwrap();
+        assert_eq!(request.command, SOCKS4_CMD_CONNECT);
+        assert_eq!(
+            request.target,
+            TargetAddr::Ip(SocketAddr::from(([127, 0, 0, 1], 80)))
+        );
+        assert_eq!(request.user_id, "alice");
+    }
+
+    #[tokio::test]
+    async fn reads_socks4a_hostname() {
+        let request = read(b"\x04\x01\x01\xbb\x00\x00\x00\x01\x00example.com\x00")
+            .await
+            .unwrap();
+        assert_eq!(
+            request.target,
+            TargetAddr::Domain("example.com".to_string(), 443)
+        );
+        assert_eq!(request.user_id, "");
+    }
+
+    #[tokio::test]
+    async fn rejects_other_versions() {
+        let err = read(b"\x05\x01\x00\x50\x7f\x00\x00\x01\x00")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_requests() {
+        for bytes in [
+            &b"\x04\x01\x00\x50\x7f"[..],
+            b"\x04\x01\x00\x50\x7f\x00\x00\x01alice",
+            b"\x04\x01\x00\x50\x00\x00\x00\x01\x00example.com",
+        ] {
+            let err = read(bytes).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_long_or_invalid_fields() {
+        let mut long = b"\x04\x01\x00\x50\x7f\x00\x00\x01".to_vec();
+        long.extend([b'a'; MAX_FIELD_LEN + 1]);
+        long.push(0);
+        assert_eq!(
+            read(&long).await.unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        let err = read(b"\x04\x01\x00\x50\x7f\x00\x00\x01\xff\x00")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn writes_reply() {
+        let mut reply = Vec::new();
+        write_reply(&mut reply, false).await.unwrap();
+        assert_eq!(reply, [0x00, 0x5b, 0, 0, 0, 0, 0, 0]);
+    }
+}
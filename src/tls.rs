@@ -0,0 +1,92 @@
+//! TLS on the listeners, so that credentials and requests don't cross
+//! untrusted networks in the clear.
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, NoClientAuth};
+use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// A server presenting the PEM certificate chain in `cert` with the PKCS#8,
+/// PKCS#1 or SEC1 key in `key`.
+///
+/// With `client_ca`, clients may present a certificate issued by one of
+/// the PEM certificates in it, and [`certificate_user`] names them.
+pub fn server_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> io::Result<Arc<ServerConfig>> {
+    let certs = read_certs(cert)?;
+    let key = read_key(key)?;
+    let verifier = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for ca in read_certs(path)? {
+                roots
+                    .add(&ca)
+                    .map_err(|err| invalid(format!("{}: {}", path.display(), err)))?;
+            }
+            AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed()
+        }
+        None => NoClientAuth::boxed(),
+    };
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)
+        .map_err(|err| invalid(err.to_string()))?;
+    Ok(Arc::new(config))
+}
+
+/// The user a verified client certificate names: the common name of its
+/// subject, or else its first email or DNS subject alternative name.
+pub fn certificate_user(cert: &Certificate) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(&cert.0).ok()?;
+    let common_name = cert
+        .subject()
+        .iter_common_name()
+        .find_map(|name| name.as_str().ok());
+    if let Some(name) = common_name {
+        return Some(name.to_string());
+    }
+    let alt_names = cert.subject_alternative_name().ok()??;
+    alt_names
+        .value
+        .general_names
+        .iter()
+        .find_map(|name| match name {
+            GeneralName::RFC822Name(name) | GeneralName::DNSName(name) => Some(name.to_string()),
+            _ => None,
+        })
+}
+
+/// The certificates in the PEM file at `path`, of which there must be one.
+pub fn read_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificate in {}", path.display())));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// The first private key in `path`.
+fn read_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(invalid(format!("no private key in {}", path.display())))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,118 @@
+//! Transparent proxying: taking connections redirected by the firewall
+//! instead of SOCKS clients, and relaying them to where they were headed.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use tokio::net::{TcpListener, TcpStream};
+
+/// How connections are redirected to the listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Intake {
+    /// iptables `REDIRECT` (NAT): the original destination is kept in the
+    /// connection tracking table, under `SO_ORIGINAL_DST`.
+    Redirect,
+    /// iptables `TPROXY`: the connection is accepted under its original
+    /// destination, which needs an `IP_TRANSPARENT` listener.
+    Tproxy,
+}
+
+impl FromStr for Intake {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "redirect" => Ok(Intake::Redirect),
+            "tproxy" => Ok(Intake::Tproxy),
+            _ => Err(format!("unknown transparent intake `{}`", s)),
+        }
+    }
+}
+
+/// Bind a listener for `intake`. TPROXY needs CAP_NET_ADMIN.
+pub async fn listen(addr: &str, intake: Intake) -> io::Result<TcpListener> {
+    match intake {
+        Intake::Redirect => TcpListener::bind(addr).await,
+        Intake::Tproxy => {
+            let addr = tokio::net::lookup_host(addr).await?.next().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "no address to listen on")
+            })?;
+            tproxy_listener(addr)
+        }
+    }
+}
+
+/// Where the client was connecting to before being redirected.
+pub fn original_destination(socket: &TcpStream, intake: Intake) -> io::Result<SocketAddr> {
+    match intake {
+        Intake::Redirect => redirect_destination(socket),
+        Intake::Tproxy => socket.local_addr(),
+    }
+}
+
+/// Whether relaying to `dest` would connect back to one of `listeners`, as
+/// happens when a firewall rule sends the proxy's own port to itself.
+/// `host_ip` is an address known to be the host's own, besides loopback.
+pub fn loops_back(
+    dest: SocketAddr,
+    listeners: impl IntoIterator<Item = SocketAddr>,
+    host_ip: Option<IpAddr>,
+) -> bool {
+    let ip = dest.ip().to_canonical();
+    listeners.into_iter().any(|listener| {
+        let listen_ip = listener.ip().to_canonical();
+        listener.port() == dest.port()
+            && (listen_ip == ip
+                || listen_ip.is_unspecified()
+                    && (ip.is_loopback() || host_ip.is_some_and(|host| host.to_canonical() == ip)))
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn redirect_destination(socket: &TcpStream) -> io::Result<SocketAddr> {
+    let socket = socket2::SockRef::from(socket);
+    let original = if socket.local_addr()?.is_ipv6() {
+        socket.original_dst_ipv6()?
+    } else {
+        socket.original_dst()?
+    };
+    original.as_socket().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "original destination is not an IP address",
+        )
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn tproxy_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    socket.set_ip_transparent(true)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn redirect_destination(_socket: &TcpStream) -> io::Result<SocketAddr> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn tproxy_listener(_addr: SocketAddr) -> io::Result<TcpListener> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "transparent proxying is only supported on Linux",
+    )
+}
@@ -0,0 +1,1002 @@
+//! The UDP relay behind UDP ASSOCIATE, as described in RFC 1928, section 7.
+
+use crate::client_stream::ClientStream;
+use crate::dns::Resolver;
+use crate::guard::DestinationGuard;
+use crate::rate_limit::RateLimiter;
+use crate::relay::DestinationPolicy;
+use crate::wire;
+use fast_socks5::server::{Socks5ServerProtocol, states};
+use fast_socks5::util::target_addr::TargetAddr;
+use fast_socks5::{ReplyError, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, Interest};
+use tokio::net::UdpSocket;
+use tokio::time::{Instant, sleep};
+use tokio_util::sync::CancellationToken;
+
+#[cfg(target_os = "linux")]
+mod icmp;
+
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+/// RFC 1928 asks for a reassembly timer of at least five seconds.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the address of a domain target is reused before it is resolved
+/// again.
+const DOMAIN_TTL: Duration = Duration::from_secs(60);
+
+/// A name lookup in progress, with the domain it is for.
+type Lookup<'a> = Pin<Box<dyn Future<Output = (String, Result<SocketAddr>)> + Send + 'a>>;
+
+/// Which datagram to drop when the queue of datagrams waiting on a name
+/// lookup is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueDrop {
+    /// Drop the datagram that doesn't fit.
+    Newest,
+    /// Drop the datagram queued first, to make room.
+    Oldest,
+}
+
+impl FromStr for QueueDrop {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "newest" => Ok(QueueDrop::Newest),
+            "oldest" => Ok(QueueDrop::Oldest),
+            _ => Err(format!("unknown queue drop policy `{}`", s)),
+        }
+    }
+}
+
+/// What to do with datagrams whose FRAG field is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FragmentPolicy {
+    /// Drop every fragment.
+    Reject,
+    /// Queue fragments in order and forward the datagram once the last one
+    /// arrives. A missing or late fragment drops the whole queue.
+    Reassemble,
+}
+
+impl FromStr for FragmentPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(FragmentPolicy::Reject),
+            "reassemble" => Ok(FragmentPolicy::Reassemble),
+            _ => Err(format!("unknown fragment policy `{}`", s)),
+        }
+    }
+}
+
+/// Which client datagrams the relay accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerFilter {
+    /// Only from the address and port the client declared in its request,
+    /// or the first one it sends from when it declared none (RFC 1928).
+    Strict,
+    /// From any port of the client's address, for clients behind NATs that
+    /// change their source port. Replies go to the last port seen.
+    Lax,
+}
+
+impl FromStr for PeerFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(PeerFilter::Strict),
+            "lax" => Ok(PeerFilter::Lax),
+            _ => Err(format!("unknown peer filter `{}`", s)),
+        }
+    }
+}
+
+/// An inclusive range of ports, parsed from `first-last` or a single port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    first: u16,
+    last: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid port range `{}`", s);
+        let (first, last) = s.split_once('-').unwrap_or((s, s));
+        let first = first.parse().map_err(|_| invalid())?;
+        let last = last.parse().map_err(|_| invalid())?;
+        if first == 0 || first > last {
+            return Err(invalid());
+        }
+        Ok(PortRange { first, last })
+    }
+}
+
+/// Which way a datagram was relayed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the client to a target.
+    Up,
+    /// From a target to the client.
+    Down,
+}
+
+impl Direction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Direction::Up => "up",
+            Direction::Down => "down",
+        }
+    }
+}
+
+/// A relayed datagram, as seen by [`UdpRelayOptions::on_datagram`].
+#[derive(Debug, Clone, Copy)]
+pub struct Datagram {
+    pub direction: Direction,
+    /// The target the datagram was sent to or came from.
+    pub peer: SocketAddr,
+    /// Payload size, without the SOCKS header.
+    pub size: usize,
+}
+
+/// Why a datagram was not relayed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// Not from the client of the association.
+    Stranger,
+    Malformed,
+    /// A fragment, with fragments rejected.
+    Fragment,
+    /// To a target the client may not reach.
+    Denied,
+    /// The target's name didn't resolve.
+    Unresolved,
+    /// The queue of datagrams waiting on a lookup was full.
+    QueueFull,
+    /// From a target without an open flow.
+    NoFlow,
+    /// Sending failed.
+    SendFailed,
+}
+
+impl DropReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DropReason::Stranger => "stranger",
+            DropReason::Malformed => "malformed",
+            DropReason::Fragment => "fragment",
+            DropReason::Denied => "denied",
+            DropReason::Unresolved => "unresolved",
+            DropReason::QueueFull => "queue_full",
+            DropReason::NoFlow => "no_flow",
+            DropReason::SendFailed => "send_failed",
+        }
+    }
+}
+
+/// A dropped datagram, as seen by [`UdpRelayOptions::on_drop`].
+#[derive(Debug, Clone, Copy)]
+pub struct Dropped {
+    /// Which way the datagram was headed.
+    pub direction: Direction,
+    pub reason: DropReason,
+}
+
+/// What an ICMP error about a datagram sent to a target says.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IcmpKind {
+    /// The target host, network or port is unreachable.
+    Unreachable,
+    /// The datagram was larger than the path allows, with the path MTU.
+    FragmentationNeeded(u32),
+}
+
+impl IcmpKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            IcmpKind::Unreachable => "unreachable",
+            IcmpKind::FragmentationNeeded(_) => "fragmentation_needed",
+        }
+    }
+}
+
+/// An ICMP error, as seen by [`UdpRelayOptions::on_icmp_error`]. Only read
+/// on Linux.
+#[derive(Debug, Clone, Copy)]
+pub struct IcmpError {
+    /// The target the offending datagram was sent to.
+    pub peer: SocketAddr,
+    pub kind: IcmpKind,
+}
+
+/// Counters of one association.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UdpStats {
+    pub datagrams_up: u64,
+    pub bytes_up: u64,
+    pub datagrams_down: u64,
+    pub bytes_down: u64,
+    /// Datagrams not relayed: from strangers, malformed, rejected fragments,
+    /// to refused targets, from targets without a flow, overflowing the
+    /// lookup queue, or failing to send.
+    pub dropped: u64,
+    /// The part of `dropped` that overflowed the lookup queue.
+    pub overflowed: u64,
+    /// ICMP errors about datagrams sent to targets.
+    pub icmp_errors: u64,
+}
+
+impl UdpStats {
+    fn record(&mut self, datagram: &Datagram) {
+        let size = datagram.size as u64;
+        match datagram.direction {
+            Direction::Up => {
+                self.datagrams_up += 1;
+                self.bytes_up += size;
+            }
+            Direction::Down => {
+                self.datagrams_down += 1;
+                self.bytes_down += size;
+            }
+        }
+    }
+}
+
+impl fmt::Display for UdpStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} datagrams ({} bytes) up, {} datagrams ({} bytes) down, {} dropped ({} on a full queue), {} ICMP errors",
+            self.datagrams_up,
+            self.bytes_up,
+            self.datagrams_down,
+            self.bytes_down,
+            self.dropped,
+            self.overflowed,
+            self.icmp_errors
+        )
+    }
+}
+
+pub struct UdpRelayOptions<'a> {
+    /// DST.ADDR and DST.PORT of the UDP ASSOCIATE request: where the client
+    /// will send datagrams from, either part possibly left as zero.
+    pub declared_client: &'a TargetAddr,
+    /// Address of the client's control connection, standing in for an
+    /// unspecified declared address.
+    pub control_peer: IpAddr,
+    pub peer_filter: PeerFilter,
+    /// Address sent to the client as the relay's BND.ADDR.
+    pub reply_ip: IpAddr,
+    /// Local address the client sends datagrams to, or the unspecified
+    /// address of the reply address's family.
+    pub bind_ip: Option<IpAddr>,
+    /// Ports the client-facing socket may use, instead of an ephemeral one.
+    pub ports: Option<PortRange>,
+    /// Local address datagrams are sent to targets from.
+    pub outbound_ip: Option<IpAddr>,
+    pub fragments: FragmentPolicy,
+    pub resolver: &'a Resolver,
+    /// Checked against the resolved target of every client datagram, as for
+    /// CONNECT.
+    pub guard: DestinationGuard,
+    pub policy: &'a dyn DestinationPolicy,
+    /// Paces datagrams in both directions by their payload size.
+    pub limiter: &'a RateLimiter,
+    /// Time after which a target that hasn't exchanged datagrams with the
+    /// client may no longer send any.
+    pub flow_idle_timeout: Duration,
+    /// Maximum number of targets per association; the least recently used
+    /// one is evicted to make room.
+    pub max_flows: usize,
+    /// Maximum number of datagrams held while their domain target is being
+    /// resolved; datagrams to other targets keep flowing meanwhile.
+    pub queue_size: usize,
+    pub queue_drop: QueueDrop,
+    /// End the association after this long, even with the control
+    /// connection still open.
+    pub lifetime: Option<Duration>,
+    /// Called with every relayed datagram.
+    pub on_datagram: Option<&'a (dyn Fn(&Datagram) + Sync)>,
+    /// Called with every dropped datagram.
+    pub on_drop: Option<&'a (dyn Fn(&Dropped) + Sync)>,
+    /// Called with every ICMP error. An unreachable target also loses its
+    /// flow, so it can't send to the client until the client sends to it
+    /// again.
+    pub on_icmp_error: Option<&'a (dyn Fn(&IcmpError) + Sync)>,
+}
+
+/// Serve a UDP ASSOCIATE request: open a relay socket, send its address to
+/// the client and relay datagrams until the control connection closes, the
+/// lifetime runs out or `shutdown` is cancelled.
+pub async fn run_udp_relay(
+    proto: Socks5ServerProtocol<ClientStream, states::CommandRead>,
+    options: &UdpRelayOptions<'_>,
+    shutdown: &CancellationToken,
+) -> Result<UdpStats> {
+    let sockets = bind_client_socket(options)
+        .await
+        .and_then(|client| Ok((client, bind_outbound(options.outbound_ip)?)));
+    let (client_socket, outbound) = match sockets {
+        Ok(sockets) => sockets,
+        Err(err) => {
+            proto.reply_error(&ReplyError::GeneralFailure).await?;
+            return Err(err.into());
+        }
+    };
+    let bnd_addr = SocketAddr::new(options.reply_ip, client_socket.local_addr()?.port());
+    let mut control = proto.reply_success(bnd_addr).await?;
+    debug!("UDP relay listening on {}", bnd_addr);
+
+    let mut relay = Relay {
+        options,
+        outbound,
+        client_addr: None,
+        reassembly: None,
+        flows: FlowTable {
+            flows: HashMap::new(),
+            idle_timeout: options.flow_idle_timeout,
+            max_flows: options.max_flows.max(1),
+        },
+        domains: DomainCache {
+            addrs: HashMap::new(),
+            max_entries: options.max_flows.max(1),
+        },
+        pending: VecDeque::new(),
+        stats: UdpStats::default(),
+    };
+    let mut lookup: Option<Lookup<'_>> = None;
+    let mut client_buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut target_buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut control_buf = [0u8; 64];
+    let expiry = sleep(options.lifetime.unwrap_or(Duration::MAX));
+    tokio::pin!(expiry);
+    loop {
+        tokio::select! {
+            // The association lasts as long as the control connection.
+            read = control.read(&mut control_buf) => match read {
+                Ok(0) | Err(_) => return Ok(relay.stats),
+                Ok(_) => {}
+            },
+            _ = shutdown.cancelled() => return Ok(relay.stats),
+            _ = &mut expiry, if options.lifetime.is_some() => {
+                debug!("UDP association expired after {:?}", options.lifetime);
+                return Ok(relay.stats);
+            }
+            received = client_socket.recv_from(&mut client_buf) => match received {
+                Ok((n, from)) => relay.relay_from_client(&client_buf[..n], from).await,
+                Err(err) => debug!("Receiving from UDP client failed: {}", err),
+            },
+            received = relay.outbound.recv_from(&mut target_buf) => match received {
+                Ok((n, from)) => relay.relay_to_client(&client_socket, &target_buf[..n], from).await?,
+                Err(err) => debug!("Receiving from UDP target failed: {}", err),
+            },
+            ready = relay.outbound.ready(Interest::ERROR), if cfg!(target_os = "linux") => {
+                if ready.is_ok() {
+                    relay.icmp_errors();
+                }
+            }
+            (domain, resolved) = async { lookup.as_mut().expect("a lookup is running").await },
+                if lookup.is_some() =>
+            {
+                lookup = None;
+                relay.resolved(domain, resolved).await;
+            }
+        }
+        if lookup.is_none() {
+            lookup = relay.next_lookup();
+        }
+    }
+}
+
+/// The state of one association.
+struct Relay<'a> {
+    options: &'a UdpRelayOptions<'a>,
+    outbound: UdpSocket,
+    /// Where replies go: the source of the client datagrams accepted so far.
+    client_addr: Option<SocketAddr>,
+    reassembly: Option<Reassembly>,
+    flows: FlowTable,
+    domains: DomainCache,
+    /// Datagrams waiting on the lookup of their domain target, oldest first.
+    pending: VecDeque<Pending>,
+    stats: UdpStats,
+}
+
+struct Pending {
+    /// Lowercase, as in the domain cache.
+    domain: String,
+    port: u16,
+    data: Vec<u8>,
+}
+
+/// The targets of an association, like the mapping table of a NAT: only
+/// targets the client recently sent a datagram to may send datagrams back.
+struct FlowTable {
+    /// When each target last exchanged a datagram with the client.
+    flows: HashMap<SocketAddr, Instant>,
+    idle_timeout: Duration,
+    max_flows: usize,
+}
+
+impl FlowTable {
+    /// Open or refresh the flow to `target`.
+    fn touch(&mut self, target: SocketAddr) {
+        if !self.flows.contains_key(&target) && self.flows.len() >= self.max_flows {
+            let idle_timeout = self.idle_timeout;
+            self.flows.retain(|_, last| last.elapsed() <= idle_timeout);
+            if self.flows.len() >= self.max_flows {
+                let oldest = self
+                    .flows
+                    .iter()
+                    .min_by_key(|(_, last)| **last)
+                    .map(|(peer, _)| *peer);
+                if let Some(oldest) = oldest {
+                    debug!("Evicting UDP flow to {}", oldest);
+                    self.flows.remove(&oldest);
+                }
+            }
+        }
+        self.flows.insert(target, Instant::now());
+    }
+
+    /// Close the flow to `peer`, if open.
+    fn close(&mut self, peer: SocketAddr) {
+        self.flows.remove(&peer);
+    }
+
+    /// Whether `peer` may send a datagram to the client, refreshing its flow
+    /// if so.
+    fn admit(&mut self, peer: SocketAddr) -> bool {
+        match self.flows.get_mut(&peer) {
+            Some(last) if last.elapsed() <= self.idle_timeout => {
+                *last = Instant::now();
+                true
+            }
+            Some(_) => {
+                self.flows.remove(&peer);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+/// The addresses domain targets resolved to, so that datagrams to a domain
+/// don't wait on a lookup each.
+struct DomainCache {
+    /// Lowercase domains, with their address and when it was resolved.
+    addrs: HashMap<String, (IpAddr, Instant)>,
+    max_entries: usize,
+}
+
+impl DomainCache {
+    fn get(&self, domain: &str) -> Option<IpAddr> {
+        self.addrs
+            .get(domain)
+            .filter(|(_, resolved)| resolved.elapsed() <= DOMAIN_TTL)
+            .map(|(ip, _)| *ip)
+    }
+
+    fn insert(&mut self, domain: String, ip: IpAddr) {
+        if !self.addrs.contains_key(&domain) && self.addrs.len() >= self.max_entries {
+            self.addrs
+                .retain(|_, (_, resolved)| resolved.elapsed() <= DOMAIN_TTL);
+            if self.addrs.len() >= self.max_entries {
+                let oldest = self
+                    .addrs
+                    .iter()
+                    .min_by_key(|(_, (_, resolved))| *resolved)
+                    .map(|(domain, _)| domain.clone());
+                if let Some(oldest) = oldest {
+                    self.addrs.remove(&oldest);
+                }
+            }
+        }
+        self.addrs.insert(domain, (ip, Instant::now()));
+    }
+}
+
+/// A datagram arriving in fragments.
+struct Reassembly {
+    target: TargetAddr,
+    next_position: u8,
+    data: Vec<u8>,
+    started: Instant,
+}
+
+/// Add a fragment to `reassembly`, returning the datagram once complete.
+///
+/// The low seven bits of `frag` are the fragment's position, starting at
+/// one; the high bit marks the last fragment.
+fn reassemble(
+    reassembly: &mut Option<Reassembly>,
+    frag: u8,
+    target: TargetAddr,
+    data: &[u8],
+) -> Option<(TargetAddr, Vec<u8>)> {
+    let position = frag & 0x7f;
+    let last = frag & 0x80 != 0;
+    if reassembly
+        .as_ref()
+        .is_some_and(|queue| queue.started.elapsed() > REASSEMBLY_TIMEOUT)
+    {
+        debug!(
+            "Dropping UDP reassembly queue after {:?}",
+            REASSEMBLY_TIMEOUT
+        );
+        *reassembly = None;
+    }
+
+    if position == 1 {
+        *reassembly = Some(Reassembly {
+            target,
+            next_position: 2,
+            data: data.to_vec(),
+            started: Instant::now(),
+        });
+    } else {
+        match reassembly {
+            Some(queue) if queue.next_position == position => {
+                queue.data.extend_from_slice(data);
+                queue.next_position += 1;
+            }
+            _ => {
+                debug!("Dropping UDP reassembly queue on fragment {}", position);
+                *reassembly = None;
+                return None;
+            }
+        }
+    }
+
+    if reassembly
+        .as_ref()
+        .is_some_and(|queue| queue.data.len() > MAX_DATAGRAM_SIZE)
+    {
+        debug!(
+            "Dropping UDP reassembly queue over {} bytes",
+            MAX_DATAGRAM_SIZE
+        );
+        *reassembly = None;
+        return None;
+    }
+    if !last {
+        return None;
+    }
+    reassembly.take().map(|queue| (queue.target, queue.data))
+}
+
+impl Relay<'_> {
+    fn relayed(&mut self, datagram: Datagram) {
+        self.stats.record(&datagram);
+        if let Some(on_datagram) = self.options.on_datagram {
+            on_datagram(&datagram);
+        }
+    }
+
+    fn dropped(&mut self, direction: Direction, reason: DropReason) {
+        self.stats.dropped += 1;
+        if reason == DropReason::QueueFull {
+            self.stats.overflowed += 1;
+        }
+        if let Some(on_drop) = self.options.on_drop {
+            on_drop(&Dropped { direction, reason });
+        }
+    }
+
+    /// Handle the ICMP errors queued on the outbound socket.
+    fn icmp_errors(&mut self) {
+        #[cfg(target_os = "linux")]
+        for error in icmp::read_errors(&self.outbound) {
+            debug!("ICMP error for UDP target {}: {:?}", error.peer, error.kind);
+            self.stats.icmp_errors += 1;
+            if error.kind == IcmpKind::Unreachable {
+                self.flows.close(error.peer);
+            }
+            if let Some(on_icmp_error) = self.options.on_icmp_error {
+                on_icmp_error(&error);
+            }
+        }
+    }
+
+    /// Whether a datagram from `from` comes from the client, learning its
+    /// address if so.
+    fn accept_client(&mut self, from: SocketAddr) -> bool {
+        let (declared_ip, declared_port) = match self.options.declared_client {
+            TargetAddr::Ip(addr) => (Some(addr.ip().to_canonical()), addr.port()),
+            TargetAddr::Domain(_, port) => (None, *port),
+        };
+        let client_ip = declared_ip
+            .filter(|ip| !ip.is_unspecified())
+            .unwrap_or(self.options.control_peer.to_canonical());
+        if from.ip() != client_ip {
+            return false;
+        }
+        match self.options.peer_filter {
+            PeerFilter::Strict => match self.client_addr {
+                Some(client_addr) => client_addr == from,
+                None if declared_port != 0 && declared_port != from.port() => false,
+                None => {
+                    self.client_addr = Some(from);
+                    true
+                }
+            },
+            PeerFilter::Lax => {
+                self.client_addr = Some(from);
+                true
+            }
+        }
+    }
+
+    async fn relay_from_client(&mut self, packet: &[u8], from: SocketAddr) {
+        let from = canonical(from);
+        if !self.accept_client(from) {
+            debug!("Dropping UDP datagram from {}, not the client", from);
+            self.dropped(Direction::Up, DropReason::Stranger);
+            return;
+        }
+        let Some((frag, target, data)) = parse_header(packet).await else {
+            debug!("Dropping malformed UDP datagram from {}", from);
+            self.dropped(Direction::Up, DropReason::Malformed);
+            return;
+        };
+
+        let (target, data) = match (frag, self.options.fragments) {
+            (0, _) => (target, data.to_vec()),
+            (_, FragmentPolicy::Reject) => {
+                debug!(
+                    "Dropping UDP fragment from {}, fragments are rejected",
+                    from
+                );
+                self.dropped(Direction::Up, DropReason::Fragment);
+                return;
+            }
+            (_, FragmentPolicy::Reassemble) => {
+                match reassemble(&mut self.reassembly, frag, target, data) {
+                    Some(datagram) => datagram,
+                    None => return,
+                }
+            }
+        };
+        match target {
+            TargetAddr::Ip(addr) => self.send_to_target(None, canonical(addr), &data).await,
+            TargetAddr::Domain(domain, port) => {
+                let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+                match self.domains.get(&domain) {
+                    Some(ip) => {
+                        self.send_to_target(Some(&domain), SocketAddr::new(ip, port), &data)
+                            .await
+                    }
+                    None => self.enqueue(Pending { domain, port, data }),
+                }
+            }
+        }
+    }
+
+    /// Hold a datagram until its target is resolved, making room as the
+    /// drop policy says if the queue is full.
+    fn enqueue(&mut self, datagram: Pending) {
+        if self.pending.len() >= self.options.queue_size.max(1) {
+            self.dropped(Direction::Up, DropReason::QueueFull);
+            match self.options.queue_drop {
+                QueueDrop::Newest => {
+                    debug!(
+                        "Dropping UDP datagram to {}, the lookup queue is full",
+                        datagram.domain
+                    );
+                    return;
+                }
+                QueueDrop::Oldest => {
+                    if let Some(oldest) = self.pending.pop_front() {
+                        debug!(
+                            "Dropping UDP datagram to {}, the lookup queue is full",
+                            oldest.domain
+                        );
+                    }
+                }
+            }
+        }
+        self.pending.push_back(datagram);
+    }
+
+    /// Send, or drop if the lookup failed, the datagrams waiting on `domain`.
+    async fn resolved(&mut self, domain: String, resolved: Result<SocketAddr>) {
+        let (ready, waiting) = self
+            .pending
+            .drain(..)
+            .partition(|datagram| datagram.domain == domain);
+        self.pending = waiting;
+        let ip = match resolved {
+            Ok(addr) => canonical(addr).ip(),
+            Err(err) => {
+                debug!("Resolving UDP target {} failed: {}", domain, err);
+                for _ in &ready {
+                    self.dropped(Direction::Up, DropReason::Unresolved);
+                }
+                return;
+            }
+        };
+        self.domains.insert(domain.clone(), ip);
+        for datagram in ready {
+            let peer = SocketAddr::new(ip, datagram.port);
+            self.send_to_target(Some(&domain), peer, &datagram.data)
+                .await;
+        }
+    }
+
+    /// Send `data` to `peer`, an address of `domain` if the client named
+    /// one, counting the datagram as relayed or dropped.
+    async fn send_to_target(&mut self, domain: Option<&str>, peer: SocketAddr, data: &[u8]) {
+        if !self.options.guard.allows(peer) || !self.options.policy.permits(domain, peer) {
+            debug!("Dropping UDP datagram to {}, denied by ACL", peer);
+            self.dropped(Direction::Up, DropReason::Denied);
+            return;
+        }
+        self.options.limiter.throttle(data.len()).await;
+        match self.send_to_peer(peer, data).await {
+            Ok(()) => self.relayed(Datagram {
+                direction: Direction::Up,
+                peer,
+                size: data.len(),
+            }),
+            Err(err) => {
+                debug!("Relaying UDP datagram to {} failed: {}", peer, err);
+                self.dropped(Direction::Up, DropReason::SendFailed);
+            }
+        }
+    }
+
+    async fn send_to_peer(&mut self, peer: SocketAddr, data: &[u8]) -> Result<()> {
+        self.flows.touch(peer);
+        let local = self.outbound.local_addr()?;
+        let addr = match (local, peer) {
+            (SocketAddr::V6(local), SocketAddr::V4(addr)) if local.ip().is_unspecified() => {
+                SocketAddr::new(addr.ip().to_ipv6_mapped().into(), addr.port())
+            }
+            (local, addr) if local.is_ipv4() != addr.is_ipv4() => {
+                return Err(ReplyError::AddressTypeNotSupported.into());
+            }
+            (_, addr) => addr,
+        };
+        self.outbound.send_to(data, addr).await?;
+        Ok(())
+    }
+
+    async fn relay_to_client(
+        &mut self,
+        client_socket: &UdpSocket,
+        data: &[u8],
+        from: SocketAddr,
+    ) -> Result<()> {
+        let from = canonical(from);
+        let Some(client_addr) = self.client_addr else {
+            return Ok(());
+        };
+        if !self.flows.admit(from) {
+            debug!("Dropping UDP datagram from {}, no open flow", from);
+            self.dropped(Direction::Down, DropReason::NoFlow);
+            return Ok(());
+        }
+        let mut packet = vec![0x00, 0x00, 0x00];
+        wire::encode_addr(&mut packet, &TargetAddr::Ip(from))?;
+        packet.extend_from_slice(data);
+        self.options.limiter.throttle(data.len()).await;
+        match client_socket.send_to(&packet, client_addr).await {
+            Ok(_) => self.relayed(Datagram {
+                direction: Direction::Down,
+                peer: from,
+                size: data.len(),
+            }),
+            Err(err) => {
+                debug!(
+                    "Relaying UDP datagram to client {} failed: {}",
+                    client_addr, err
+                );
+                self.dropped(Direction::Down, DropReason::SendFailed);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Relay<'a> {
+    /// The lookup of the oldest waiting datagram's target, if any.
+    fn next_lookup(&self) -> Option<Lookup<'a>> {
+        let next = self.pending.front()?;
+        let resolver = self.options.resolver;
+        let domain = next.domain.clone();
+        let target = TargetAddr::Domain(domain.clone(), next.port);
+        Some(Box::pin(async move {
+            (domain, resolver.resolve(&target).await)
+        }))
+    }
+}
+
+/// Split a client datagram into its FRAG field, destination and data.
+async fn parse_header(packet: &[u8]) -> Option<(u8, TargetAddr, &[u8])> {
+    let [0x00, 0x00, frag, atyp, rest @ ..] = packet else {
+        return None;
+    };
+    let mut rest = rest;
+    let target = wire::read_addr(&mut rest, *atyp).await.ok()?;
+    Some((*frag, target, rest))
+}
+
+/// `addr`, with an IPv4-mapped IPv6 address turned into the IPv4 one.
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Bind the socket the client sends datagrams to, on a free port of the
+/// configured range if there is one.
+async fn bind_client_socket(options: &UdpRelayOptions<'_>) -> io::Result<UdpSocket> {
+    let ip = options.bind_ip.unwrap_or(unspecified(options.reply_ip));
+    let Some(PortRange { first, last }) = options.ports else {
+        return UdpSocket::bind(SocketAddr::new(ip, 0)).await;
+    };
+
+    // Start somewhere in the range, so that concurrent associations don't
+    // all probe the same ports.
+    let len = u32::from(last - first) + 1;
+    let start = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.subsec_nanos() % len);
+    for offset in 0..len {
+        let port = first + ((start + offset) % len) as u16;
+        match UdpSocket::bind(SocketAddr::new(ip, port)).await {
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse => continue,
+            result => return result,
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!("no free UDP port in {}-{}", first, last),
+    ))
+}
+
+fn unspecified(like: IpAddr) -> IpAddr {
+    match like {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    }
+}
+
+/// Bind the socket datagrams are sent to targets from: on `ip` when set,
+/// otherwise on a dual-stack socket, or IPv4 only on hosts without IPv6.
+fn bind_outbound(ip: Option<IpAddr>) -> io::Result<UdpSocket> {
+    let socket = match ip {
+        Some(ip) => bind_udp(SocketAddr::new(ip, 0), false)?,
+        None => bind_udp(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0), true)
+            .or_else(|_| bind_udp(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0), false))?,
+    };
+    let socket = UdpSocket::from_std(socket.into())?;
+    #[cfg(target_os = "linux")]
+    if let Err(err) = icmp::enable(&socket) {
+        debug!("Can't read ICMP errors of the UDP relay: {}", err);
+    }
+    Ok(socket)
+}
+
+fn bind_udp(addr: SocketAddr, dual_stack: bool) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if dual_stack {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target() -> TargetAddr {
+        TargetAddr::Ip("192.0.2.1:53".parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn parses_headers() {
+        let packet = b"\x00\x00\x00\x01\xc0\x00\x02\x01\x00\x35query";
+        assert_eq!(
+            parse_header(packet).await,
+            Some((0, target(), &b"query"[..]))
+        );
+        let packet = b"\x00\x00\x81\x03\x0bexample.com\x01\xbb";
+        assert_eq!(
+            parse_header(packet).await,
+            Some((
+                0x81,
+                TargetAddr::Domain("example.com".to_string(), 443),
+                &b""[..]
+            ))
+        );
+        for packet in [
+            &b"\x00\x00\x00"[..],
+            b"\x00\x01\x00\x01\xc0\x00\x02\x01\x00\x35",
+            b"\x00\x00\x00\x01\xc0\x00\x02",
+            b"\x00\x00\x00\x05\xc0\x00\x02\x01\x00\x35",
+        ] {
+            assert_eq!(parse_header(packet).await, None, "{:?}", packet);
+        }
+    }
+
+    #[test]
+    fn reassembles_fragments_in_order() {
+        let mut queue = None;
+        assert_eq!(reassemble(&mut queue, 1, target(), b"ab"), None);
+        assert_eq!(reassemble(&mut queue, 2, target(), b"cd"), None);
+        assert_eq!(
+            reassemble(&mut queue, 0x83, target(), b"ef"),
+            Some((target(), b"abcdef".to_vec()))
+        );
+        assert!(queue.is_none());
+
+        assert_eq!(
+            reassemble(&mut queue, 0x81, target(), b"whole"),
+            Some((target(), b"whole".to_vec()))
+        );
+    }
+
+    #[test]
+    fn drops_queue_on_missing_or_late_fragments() {
+        let mut queue = None;
+        reassemble(&mut queue, 1, target(), b"ab");
+        assert_eq!(reassemble(&mut queue, 0x83, target(), b"ef"), None);
+        assert!(queue.is_none());
+        assert_eq!(reassemble(&mut queue, 0x82, target(), b"cd"), None);
+
+        reassemble(&mut queue, 1, target(), b"ab");
+        if let Some(queue) = &mut queue {
+            queue.started = Instant::now() - REASSEMBLY_TIMEOUT - Duration::from_secs(1);
+        }
+        assert_eq!(reassemble(&mut queue, 0x82, target(), b"cd"), None);
+        assert!(queue.is_none());
+    }
+
+    #[test]
+    fn drops_oversized_datagrams() {
+        let mut queue = None;
+        let half = vec![0u8; MAX_DATAGRAM_SIZE / 2 + 1];
+        reassemble(&mut queue, 1, target(), &half);
+        assert_eq!(reassemble(&mut queue, 0x82, target(), &half), None);
+        assert!(queue.is_none());
+    }
+
+    #[test]
+    fn parses_port_ranges() {
+        assert_eq!(
+            "40000-40100".parse(),
+            Ok(PortRange {
+                first: 40000,
+                last: 40100
+            })
+        );
+        assert_eq!(
+            "5353".parse(),
+            Ok(PortRange {
+                first: 5353,
+                last: 5353
+            })
+        );
+        for range in ["0-10", "20-10", "a-b", "70000"] {
+            assert!(range.parse::<PortRange>().is_err(), "{}", range);
+        }
+    }
+}
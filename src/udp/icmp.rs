@@ -0,0 +1,94 @@
+//! ICMP errors for datagrams sent to targets, read from the error queue of
+//! the outbound socket with `IP_RECVERR`.
+
+use super::{IcmpError, IcmpKind};
+use nix::libc;
+use nix::sys::socket::{
+    ControlMessageOwned, MsgFlags, SockaddrStorage, recvmsg, setsockopt, sockopt,
+};
+use std::io::{self, IoSliceMut};
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::AsRawFd;
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+
+/// Queue the ICMP errors of `socket` instead of dropping them. IPv4 errors
+/// are queued on dual-stack sockets too.
+pub fn enable(socket: &UdpSocket) -> io::Result<()> {
+    if socket.local_addr()?.is_ipv6() {
+        setsockopt(socket, sockopt::Ipv6RecvErr, &true)?;
+        // Only for the IPv4-mapped part; an IPv6-only socket doesn't need it.
+        let _ = setsockopt(socket, sockopt::Ipv4RecvErr, &true);
+    } else {
+        setsockopt(socket, sockopt::Ipv4RecvErr, &true)?;
+    }
+    Ok(())
+}
+
+/// Take every queued error off `socket`.
+pub fn read_errors(socket: &UdpSocket) -> Vec<IcmpError> {
+    let mut errors = Vec::new();
+    loop {
+        match socket.try_io(Interest::ERROR, || read_error(socket)) {
+            Ok(Some(error)) => errors.push(error),
+            Ok(None) => {}
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(err) => {
+                debug!("Reading the UDP error queue failed: {}", err);
+                break;
+            }
+        }
+    }
+    // The last error is also pending on the socket itself, where it would
+    // fail the next receive.
+    let _ = socket.take_error();
+    errors
+}
+
+/// One queued error, or `None` for one that isn't about an unreachable
+/// target or too large a datagram.
+fn read_error(socket: &UdpSocket) -> io::Result<Option<IcmpError>> {
+    // The error comes with the start of the datagram that caused it, which
+    // isn't needed.
+    let mut data = [0u8; 64];
+    let mut iov = [IoSliceMut::new(&mut data)];
+    let mut control = nix::cmsg_space!(libc::sock_extended_err, libc::sockaddr_in6);
+    let msg = recvmsg::<SockaddrStorage>(
+        socket.as_raw_fd(),
+        &mut iov,
+        Some(&mut control),
+        MsgFlags::MSG_ERRQUEUE | MsgFlags::MSG_DONTWAIT,
+    )?;
+    // Where the datagram was sent, not who reported the error.
+    let peer = msg.address.and_then(|addr| {
+        addr.as_sockaddr_in()
+            .map(|addr| SocketAddr::from(SocketAddrV4::from(*addr)))
+            .or_else(|| {
+                addr.as_sockaddr_in6()
+                    .map(|addr| SocketAddr::from(SocketAddrV6::from(*addr)))
+            })
+    });
+    let Some(peer) = peer else {
+        return Ok(None);
+    };
+    for message in msg.cmsgs()? {
+        let err = match message {
+            ControlMessageOwned::Ipv4RecvErr(err, _) | ControlMessageOwned::Ipv6RecvErr(err, _) => {
+                err
+            }
+            _ => continue,
+        };
+        let kind = match err.ee_errno as i32 {
+            libc::EMSGSIZE => IcmpKind::FragmentationNeeded(err.ee_info),
+            libc::ECONNREFUSED | libc::EHOSTUNREACH | libc::ENETUNREACH | libc::EHOSTDOWN => {
+                IcmpKind::Unreachable
+            }
+            _ => continue,
+        };
+        return Ok(Some(IcmpError {
+            peer: super::canonical(peer),
+            kind,
+        }));
+    }
+    Ok(None)
+}
@@ -0,0 +1,382 @@
+//! Dialing CONNECT targets through another SOCKS5 or HTTP proxy.
+
+use crate::outbound::Outbound;
+use crate::wire::{
+    self, SOCKS5_AUTH_METHOD_NONE, SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE, SOCKS5_AUTH_METHOD_PASSWORD,
+    SOCKS5_CMD_TCP_CONNECT, SOCKS5_PASSWORD_VERSION, SOCKS5_REPLY_SUCCEEDED, SOCKS5_VERSION,
+};
+use base64::Engine as _;
+use fast_socks5::util::target_addr::TargetAddr;
+use fast_socks5::{ReplyError, Result, SocksError};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+/// Response headers from an HTTP proxy larger than this are rejected.
+const MAX_HTTP_RESPONSE_LEN: usize = 8 * 1024;
+
+/// A tunnel being opened, with its lease.
+pub type Tunnel<'a> = Pin<Box<dyn Future<Output = Result<(Outbound, Lease)>> + Send + 'a>>;
+
+/// Opens tunnels to targets through other proxies, in place of connecting
+/// to them directly.
+pub trait OutboundConnector: Send + Sync {
+    /// A tunnel to `target`, with the lease to hold for as long as it is in
+    /// use.
+    fn connect<'a>(&'a self, target: &'a TargetAddr) -> Tunnel<'a>;
+}
+
+/// Counts a tunnel as open until dropped, for balancing by connection count.
+#[derive(Debug, Default)]
+pub struct Lease(Option<Arc<AtomicUsize>>);
+
+impl Lease {
+    /// A lease counted in `open` until dropped.
+    pub fn counted(open: Arc<AtomicUsize>) -> Self {
+        open.fetch_add(1, Ordering::Relaxed);
+        Lease(Some(open))
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if let Some(open) = &self.0 {
+            open.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scheme {
+    Socks5,
+    Http,
+}
+
+/// An upstream proxy, parsed from `socks5://[user:pass@]host:port` or
+/// `http://[user:pass@]host:port`, with `unix:/path` in place of
+/// `host:port` for a proxy listening on a Unix socket. A SOCKS5 proxy taking
+/// clients over WebSocket is `socks5+ws://[user:pass@]host:port[/path]`, and
+/// over QUIC `socks5+quic://[user:pass@]host:port`.
+///
+/// Domain targets are passed to the upstream unresolved.
+#[derive(Clone)]
+pub struct Upstream {
+    scheme: Scheme,
+    addr: String,
+    credentials: Option<(String, String)>,
+    /// The path of the WebSocket the proxy is reached through.
+    websocket: Option<String>,
+    /// The QUIC connection the proxy is reached through, shared by clones.
+    #[cfg(feature = "quic")]
+    quic: Option<Arc<crate::quic::Dialer>>,
+}
+
+impl PartialEq for Upstream {
+    fn eq(&self, other: &Self) -> bool {
+        (&self.scheme, &self.addr, &self.credentials, &self.websocket)
+            == (
+                &other.scheme,
+                &other.addr,
+                &other.credentials,
+                &other.websocket,
+            )
+            && self.is_quic() == other.is_quic()
+    }
+}
+
+impl Eq for Upstream {}
+
+impl fmt::Debug for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Keep the password out of logs and `--help` output.
+        f.debug_struct("Upstream")
+            .field("scheme", &self.scheme)
+            .field("addr", &self.addr)
+            .field("username", &self.credentials.as_ref().map(|(user, _)| user))
+            .field("websocket", &self.websocket)
+            .field("quic", &self.is_quic())
+            .finish()
+    }
+}
+
+impl FromStr for Upstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = s
+            .split_once("://")
+            .ok_or_else(|| format!("upstream `{}` has no scheme", s))?;
+        let (scheme, websocket, quic) = match scheme {
+            "socks5" | "socks5h" => (Scheme::Socks5, false, false),
+            #[cfg(feature = "websocket")]
+            "socks5+ws" => (Scheme::Socks5, true, false),
+            #[cfg(feature = "quic")]
+            "socks5+quic" => (Scheme::Socks5, false, true),
+            #[cfg(not(feature = "websocket"))]
+            "socks5+ws" => {
+                return Err(format!(
+                    "upstream `{}` needs the websocket feature, which this build lacks",
+                    s
+                ));
+            }
+            #[cfg(not(feature = "quic"))]
+            "socks5+quic" => {
+                return Err(format!(
+                    "upstream `{}` needs the quic feature, which this build lacks",
+                    s
+                ));
+            }
+            "http" => (Scheme::Http, false, false),
+            _ => return Err(format!("unsupported upstream scheme `{}`", scheme)),
+        };
+
+        let rest = rest.trim_end_matches('/');
+        let (credentials, addr) = match rest.rsplit_once('@') {
+            Some((userinfo, addr)) => {
+                let (user, pass) = userinfo
+                    .split_once(':')
+                    .ok_or_else(|| format!("upstream credentials in `{}` need a password", s))?;
+                (Some((user.to_string(), pass.to_string())), addr)
+            }
+            None => (None, rest),
+        };
+        // A Unix socket path runs to the end, so its WebSocket is at `/`.
+        let (addr, websocket) = match addr.find('/').filter(|_| unix_path(addr).is_none()) {
+            _ if !websocket => (addr, None),
+            Some(at) => (&addr[..at], Some(addr[at..].to_string())),
+            None => (addr, Some("/".to_string())),
+        };
+        match unix_path(addr) {
+            Some(_) if quic => {
+                return Err(format!("upstream `{}` can't be QUIC on a Unix socket", s));
+            }
+            Some("") => {
+                return Err(format!("upstream `{}` needs a socket path", s));
+            }
+            Some(_) => {}
+            None if !addr
+                .rsplit_once(':')
+                .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok()) =>
+            {
+                return Err(format!("upstream `{}` needs a host and port", s));
+            }
+            None => {}
+        }
+
+        Ok(Upstream {
+            scheme,
+            addr: addr.to_string(),
+            credentials,
+            websocket,
+            #[cfg(feature = "quic")]
+            quic: quic.then(Default::default),
+        })
+    }
+}
+
+impl OutboundConnector for Upstream {
+    fn connect<'a>(&'a self, target: &'a TargetAddr) -> Tunnel<'a> {
+        Box::pin(async move { Ok((self.tunnel(target).await?, Lease::default())) })
+    }
+}
+
+impl Upstream {
+    /// The proxy's `host:port`, or `unix:/path`.
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    fn is_quic(&self) -> bool {
+        #[cfg(feature = "quic")]
+        return self.quic.is_some();
+        #[cfg(not(feature = "quic"))]
+        false
+    }
+
+    /// Open a tunnel to `target` through the upstream proxy.
+    pub async fn tunnel(&self, target: &TargetAddr) -> Result<Outbound> {
+        let mut stream = self.dial().await?;
+        #[cfg(feature = "websocket")]
+        if let Some(path) = &self.websocket {
+            let host = match unix_path(&self.addr) {
+                Some(_) => "localhost",
+                None => &self.addr,
+            };
+            let url = format!("ws://{}{}", host, path);
+            stream = Outbound::WebSocket(Box::new(crate::websocket::connect(stream, &url).await?));
+        }
+        match self.scheme {
+            Scheme::Socks5 => self.socks5_handshake(&mut stream, target).await?,
+            Scheme::Http => self.http_connect(&mut stream, target).await?,
+        }
+        Ok(stream)
+    }
+
+    async fn dial(&self) -> Result<Outbound> {
+        #[cfg(feature = "quic")]
+        if let Some(dialer) = &self.quic {
+            return Ok(Outbound::Quic(Box::new(dialer.open(&self.addr).await?)));
+        }
+        match unix_path(&self.addr) {
+            #[cfg(unix)]
+            Some(path) => Ok(Outbound::Unix(UnixStream::connect(path).await?)),
+            #[cfg(not(unix))]
+            Some(_) => Err(upstream_error(format!(
+                "upstream {} is a Unix socket, which this platform lacks",
+                self.addr
+            ))),
+            None => Ok(Outbound::Tcp(TcpStream::connect(&self.addr).await?)),
+        }
+    }
+
+    async fn socks5_handshake<S>(&self, stream: &mut S, target: &TargetAddr) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let method = match self.credentials {
+            Some(_) => SOCKS5_AUTH_METHOD_PASSWORD,
+            None => SOCKS5_AUTH_METHOD_NONE,
+        };
+        stream.write_all(&[SOCKS5_VERSION, 1, method]).await?;
+
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice).await?;
+        if choice[0] != SOCKS5_VERSION {
+            return Err(upstream_error(format!(
+                "upstream {} is not a SOCKS5 server",
+                self.addr
+            )));
+        }
+        match choice[1] {
+            SOCKS5_AUTH_METHOD_NONE => {}
+            SOCKS5_AUTH_METHOD_PASSWORD if method == SOCKS5_AUTH_METHOD_PASSWORD => {
+                self.socks5_password_auth(stream).await?;
+            }
+            SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE => {
+                return Err(upstream_error(format!(
+                    "upstream {} accepted none of our authentication methods",
+                    self.addr
+                )));
+            }
+            other => {
+                return Err(upstream_error(format!(
+                    "upstream {} chose unexpected authentication method {:#04x}",
+                    self.addr, other
+                )));
+            }
+        }
+
+        let mut request = vec![SOCKS5_VERSION, SOCKS5_CMD_TCP_CONNECT, 0x00];
+        wire::encode_addr(&mut request, target)?;
+        stream.write_all(&request).await?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        let [version, code, _reserved, atyp] = reply;
+        if version != SOCKS5_VERSION {
+            return Err(upstream_error(format!(
+                "invalid reply version {:#04x} from upstream {}",
+                version, self.addr
+            )));
+        }
+        wire::read_addr(stream, atyp).await?;
+        if code != SOCKS5_REPLY_SUCCEEDED {
+            return Err(wire::reply_error_from_code(code).into());
+        }
+        Ok(())
+    }
+
+    async fn socks5_password_auth<S>(&self, stream: &mut S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (user, pass) = self
+            .credentials
+            .as_ref()
+            .expect("password method needs credentials");
+        let (Ok(user_len), Ok(pass_len)) = (u8::try_from(user.len()), u8::try_from(pass.len()))
+        else {
+            return Err(upstream_error(
+                "upstream username and password must be at most 255 bytes".to_string(),
+            ));
+        };
+
+        let mut request = vec![SOCKS5_PASSWORD_VERSION, user_len];
+        request.extend_from_slice(user.as_bytes());
+        request.push(pass_len);
+        request.extend_from_slice(pass.as_bytes());
+        stream.write_all(&request).await?;
+
+        let mut status = [0u8; 2];
+        stream.read_exact(&mut status).await?;
+        if status[1] != 0x00 {
+            return Err(upstream_error(format!(
+                "upstream {} rejected our credentials",
+                self.addr
+            )));
+        }
+        Ok(())
+    }
+
+    async fn http_connect<S>(&self, stream: &mut S, target: &TargetAddr) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let authority = match target {
+            TargetAddr::Ip(addr) => addr.to_string(),
+            TargetAddr::Domain(domain, port) => format!("{}:{}", domain, port),
+        };
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+        if let Some((user, pass)) = &self.credentials {
+            let token =
+                base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, pass));
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read byte by byte so no tunneled data is consumed with the headers.
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() >= MAX_HTTP_RESPONSE_LEN {
+                return Err(upstream_error(format!(
+                    "oversized CONNECT response from upstream {}",
+                    self.addr
+                )));
+            }
+            response.push(stream.read_u8().await?);
+        }
+
+        let status_line = response
+            .split(|&b| b == b'\r')
+            .next()
+            .map(String::from_utf8_lossy)
+            .unwrap_or_default();
+        let status = status_line.split_whitespace().nth(1);
+        match status {
+            Some("200") => Ok(()),
+            Some("403") | Some("407") => Err(ReplyError::ConnectionNotAllowed.into()),
+            Some("502") | Some("503") | Some("504") => Err(ReplyError::HostUnreachable.into()),
+            _ => Err(upstream_error(format!(
+                "upstream {} refused CONNECT: {}",
+                self.addr, status_line
+            ))),
+        }
+    }
+}
+
+/// The socket path of a `unix:/path` address.
+fn unix_path(addr: &str) -> Option<&str> {
+    addr.strip_prefix("unix:")
+}
+
+fn upstream_error(message: String) -> SocksError {
+    SocksError::Other(anyhow::anyhow!(message))
+}
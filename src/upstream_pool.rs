@@ -0,0 +1,151 @@
+//! Spreading tunnels over several upstream proxies, and failing over from
+//! the ones that stop working.
+
+use crate::upstream::{Lease, OutboundConnector, Tunnel, Upstream};
+use fast_socks5::SocksError;
+use fast_socks5::util::target_addr::TargetAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::time::{MissedTickBehavior, interval, timeout};
+
+/// How the pool picks the upstream of each tunnel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Balance {
+    RoundRobin,
+    /// The upstream with the fewest open tunnels, in turn on ties.
+    LeastConnections,
+}
+
+impl FromStr for Balance {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Balance::RoundRobin),
+            "least-connections" => Ok(Balance::LeastConnections),
+            _ => Err(format!("unknown upstream balance `{}`", s)),
+        }
+    }
+}
+
+struct Member {
+    upstream: Upstream,
+    /// Cleared when the upstream fails, set again once a check passes.
+    healthy: AtomicBool,
+    open: Arc<AtomicUsize>,
+}
+
+/// Upstreams tried in the order of the balance, healthy ones first.
+///
+/// A tunnel failing because of the upstream itself, or not opening within
+/// its share of the connect timeout, moves on to the next one; a refusal of
+/// the target is passed on to the client as is.
+pub struct UpstreamPool {
+    members: Vec<Member>,
+    balance: Balance,
+    next: AtomicUsize,
+    /// The time each member gets to open a tunnel.
+    attempt_timeout: Duration,
+}
+
+impl UpstreamPool {
+    /// A pool whose tunnels all open within `connect_timeout`, which is
+    /// shared out between the members.
+    pub fn new(upstreams: Vec<Upstream>, balance: Balance, connect_timeout: Duration) -> Self {
+        let attempt_timeout = connect_timeout / upstreams.len().max(1) as u32;
+        UpstreamPool {
+            members: upstreams
+                .into_iter()
+                .map(|upstream| Member {
+                    upstream,
+                    healthy: AtomicBool::new(true),
+                    open: Arc::new(AtomicUsize::new(0)),
+                })
+                .collect(),
+            balance,
+            next: AtomicUsize::new(0),
+            attempt_timeout,
+        }
+    }
+
+    /// The members in the order to try them.
+    fn order(&self) -> Vec<&Member> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let count = self.members.len();
+        let mut members: Vec<_> = (0..count)
+            .map(|i| &self.members[(start + i) % count])
+            .collect();
+        // Stable sorts, so that the rotation breaks ties.
+        if self.balance == Balance::LeastConnections {
+            members.sort_by_key(|member| member.open.load(Ordering::Relaxed));
+        }
+        members.sort_by_key(|member| !member.healthy.load(Ordering::Relaxed));
+        members
+    }
+
+    /// Open a test tunnel to `target` through every upstream each `period`,
+    /// marking the ones that fail within `limit` as down.
+    pub async fn check_every(&self, target: TargetAddr, period: Duration, limit: Duration) {
+        let mut ticks = interval(period);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            for member in &self.members {
+                let healthy = matches!(
+                    timeout(limit, member.upstream.tunnel(&target)).await,
+                    Ok(Ok(_))
+                );
+                member.set_healthy(healthy);
+            }
+        }
+    }
+}
+
+impl Member {
+    fn set_healthy(&self, healthy: bool) {
+        if self.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            if healthy {
+                info!("Upstream {} is back up", self.upstream.addr());
+            } else {
+                warn!("Upstream {} is down", self.upstream.addr());
+            }
+        }
+    }
+}
+
+impl OutboundConnector for UpstreamPool {
+    fn connect<'a>(&'a self, target: &'a TargetAddr) -> Tunnel<'a> {
+        Box::pin(async move {
+            let mut last_err = None;
+            for member in self.order() {
+                let attempt = timeout(self.attempt_timeout, member.upstream.tunnel(target)).await;
+                let result = attempt.unwrap_or_else(|_| {
+                    Err(SocksError::Other(anyhow::anyhow!(
+                        "no tunnel within {:?}",
+                        self.attempt_timeout
+                    )))
+                });
+                match result {
+                    Ok(stream) => {
+                        member.set_healthy(true);
+                        return Ok((stream, Lease::counted(member.open.clone())));
+                    }
+                    // The upstream works, the target is what failed.
+                    Err(err @ SocksError::ReplyError(_)) => return Err(err),
+                    Err(err) => {
+                        debug!(
+                            "Failing over from upstream {}: {}",
+                            member.upstream.addr(),
+                            err
+                        );
+                        member.set_healthy(false);
+                        last_err = Some(err);
+                    }
+                }
+            }
+            Err(last_err.expect("an upstream pool is never empty"))
+        })
+    }
+}
@@ -0,0 +1,127 @@
+//! SOCKS over WebSocket, so that clients can reach the proxy through
+//! HTTP-only middleboxes and reverse proxies. Each binary message carries
+//! the next piece of the byte stream, in both directions.
+
+use futures_util::{Sink, Stream};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::{Error, Message};
+
+/// A WebSocket connection over `S`, read and written as a byte stream.
+pub struct WebSocket<S> {
+    inner: WebSocketStream<S>,
+    /// The rest of the last message received.
+    read: Vec<u8>,
+    read_pos: usize,
+    /// The length of the write waiting to be flushed.
+    unflushed: Option<usize>,
+}
+
+/// Answer the WebSocket upgrade request of a client.
+pub async fn accept<S>(stream: S) -> io::Result<WebSocket<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let inner = tokio_tungstenite::accept_async(stream)
+        .await
+        .map_err(into_io)?;
+    Ok(WebSocket::new(inner))
+}
+
+/// Upgrade `stream` to a WebSocket connection to `url`.
+pub async fn connect<S>(stream: S, url: &str) -> io::Result<WebSocket<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (inner, _) = tokio_tungstenite::client_async(url, stream)
+        .await
+        .map_err(into_io)?;
+    Ok(WebSocket::new(inner))
+}
+
+impl<S> WebSocket<S> {
+    fn new(inner: WebSocketStream<S>) -> Self {
+        WebSocket {
+            inner,
+            read: Vec::new(),
+            read_pos: 0,
+            unflushed: None,
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocket<S> {
+    /// The stream the connection runs on.
+    pub fn get_ref(&self) -> &S {
+        self.inner.get_ref()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocket<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.read_pos == this.read.len() {
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => (this.read, this.read_pos) = (data, 0),
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                // Pings are answered by the connection itself.
+                Some(Ok(_)) => {}
+                Some(Err(Error::ConnectionClosed)) => return Poll::Ready(Ok(())),
+                Some(Err(err)) => return Poll::Ready(Err(into_io(err))),
+            }
+        }
+        let n = buf.remaining().min(this.read.len() - this.read_pos);
+        buf.put_slice(&this.read[this.read_pos..this.read_pos + n]);
+        this.read_pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocket<S> {
+    /// Send `buf` as one message. The write only completes once the message
+    /// is flushed, since nothing else would flush it; a write left pending
+    /// must be retried with the same data, as `write_all` does.
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.unflushed.is_none() {
+            ready!(Pin::new(&mut this.inner).poll_ready(cx)).map_err(into_io)?;
+            Pin::new(&mut this.inner)
+                .start_send(Message::Binary(buf.to_vec()))
+                .map_err(into_io)?;
+            this.unflushed = Some(buf.len());
+        }
+        ready!(Pin::new(&mut this.inner).poll_flush(cx)).map_err(into_io)?;
+        Poll::Ready(Ok(this.unflushed.take().unwrap_or_default()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_flush(cx)
+            .map_err(into_io)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match ready!(Pin::new(&mut self.get_mut().inner).poll_close(cx)) {
+            Ok(()) | Err(Error::ConnectionClosed) => Poll::Ready(Ok(())),
+            Err(err) => Poll::Ready(Err(into_io(err))),
+        }
+    }
+}
+
+fn into_io(err: Error) -> io::Error {
+    match err {
+        Error::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
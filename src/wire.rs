@@ -0,0 +1,100 @@
+//! The pieces of the SOCKS5 wire format the server writes and parses itself,
+//! for the parts of the protocol fast-socks5 does not cover.
+
+use fast_socks5::ReplyError;
+use fast_socks5::util::target_addr::TargetAddr;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub const SOCKS5_VERSION: u8 = 0x05;
+
+pub const SOCKS5_AUTH_METHOD_NONE: u8 = 0x00;
+pub const SOCKS5_AUTH_METHOD_PASSWORD: u8 = 0x02;
+pub const SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE: u8 = 0xff;
+pub const SOCKS5_PASSWORD_VERSION: u8 = 0x01;
+
+pub const SOCKS5_CMD_TCP_CONNECT: u8 = 0x01;
+
+pub const SOCKS5_REPLY_SUCCEEDED: u8 = 0x00;
+pub const SOCKS5_REPLY_GENERAL_FAILURE: u8 = 0x01;
+pub const SOCKS5_REPLY_TTL_EXPIRED: u8 = 0x06;
+
+pub const SOCKS5_ADDR_TYPE_IPV4: u8 = 0x01;
+pub const SOCKS5_ADDR_TYPE_DOMAIN_NAME: u8 = 0x03;
+pub const SOCKS5_ADDR_TYPE_IPV6: u8 = 0x04;
+
+/// Append ATYP, the address and the port of `addr` to `buf`.
+pub fn encode_addr(buf: &mut Vec<u8>, addr: &TargetAddr) -> io::Result<()> {
+    match addr {
+        TargetAddr::Ip(SocketAddr::V4(addr)) => {
+            buf.push(SOCKS5_ADDR_TYPE_IPV4);
+            buf.extend_from_slice(&addr.ip().octets());
+            buf.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        TargetAddr::Ip(SocketAddr::V6(addr)) => {
+            buf.push(SOCKS5_ADDR_TYPE_IPV6);
+            buf.extend_from_slice(&addr.ip().octets());
+            buf.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        TargetAddr::Domain(domain, port) => {
+            let len = u8::try_from(domain.len()).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "domain name exceeds 255 bytes")
+            })?;
+            buf.push(SOCKS5_ADDR_TYPE_DOMAIN_NAME);
+            buf.push(len);
+            buf.extend_from_slice(domain.as_bytes());
+            buf.extend_from_slice(&port.to_be_bytes());
+        }
+    }
+    Ok(())
+}
+
+/// Read an address of type `atyp` followed by a port.
+pub async fn read_addr<R: AsyncRead + Unpin>(reader: &mut R, atyp: u8) -> io::Result<TargetAddr> {
+    let addr = match atyp {
+        SOCKS5_ADDR_TYPE_IPV4 => {
+            let mut ip = [0u8; 4];
+            reader.read_exact(&mut ip).await?;
+            let port = reader.read_u16().await?;
+            TargetAddr::Ip(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+        }
+        SOCKS5_ADDR_TYPE_IPV6 => {
+            let mut ip = [0u8; 16];
+            reader.read_exact(&mut ip).await?;
+            let port = reader.read_u16().await?;
+            TargetAddr::Ip(SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+        }
+        SOCKS5_ADDR_TYPE_DOMAIN_NAME => {
+            let len = reader.read_u8().await?;
+            let mut domain = vec![0u8; len as usize];
+            reader.read_exact(&mut domain).await?;
+            let domain = String::from_utf8(domain).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "domain name is not UTF-8")
+            })?;
+            let port = reader.read_u16().await?;
+            TargetAddr::Domain(domain, port)
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown address type {:#04x}", atyp),
+            ));
+        }
+    };
+    Ok(addr)
+}
+
+/// Map a REP field received from another SOCKS5 server to a reply error.
+pub fn reply_error_from_code(code: u8) -> ReplyError {
+    match code {
+        0x02 => ReplyError::ConnectionNotAllowed,
+        0x03 => ReplyError::NetworkUnreachable,
+        0x04 => ReplyError::HostUnreachable,
+        0x05 => ReplyError::ConnectionRefused,
+        0x06 => ReplyError::TtlExpired,
+        0x07 => ReplyError::CommandNotSupported,
+        0x08 => ReplyError::AddressTypeNotSupported,
+        _ => ReplyError::GeneralFailure,
+    }
+}